@@ -1,172 +1,704 @@
-// TODO: redo this whole module basically
-use super::cli::ActionOpt;
+//! Running the steps of a job in each checkout.
+use super::cache::{CompilerCache, SharedTargets};
+use super::cancel;
+use super::cargo::{Message, MESSAGE_FORMAT_SUBCOMMANDS};
+use super::diagnostics::Diagnostics;
+use super::libtest::{self, TestOutcome};
+use super::locks::{LockSpec, Locks};
+use super::logs::{self, attempt_log, read_lines, LogOptions, Logs, OutputLine, Stream};
+use super::placeholders::Placeholders;
+use super::process::{self, Stopped, Supervisor};
+use super::progress::{ProgressFile, Update};
+use super::report::{secs, Attempt, Outcome, Status};
+use super::toolchains::get_rustc_version;
 use super::Checkout;
-use crossbeam::channel::unbounded;
+use crossbeam::channel::{bounded, RecvTimeoutError};
 use crossbeam::thread::Scope;
 use failure::{Error, ResultExt};
-use std::ffi::OsStr;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use jobserver::Client;
+use std::collections::BTreeMap;
+use std::io::BufReader;
+use std::path::PathBuf;
 use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
-fn make_an_each_command(
-    install: bool,
-    toolchain: &str,
-    cargo: bool,
-    replacements: bool,
-    utility: &str,
-    args: impl IntoIterator<Item = impl AsRef<OsStr>>,
-) -> Command {
-    let mut cmd = Command::new("rustup");
-    cmd.arg("run");
-    if install {
-        cmd.arg("--install");
+/// How many lines of output can be waiting to be logged, per step.
+const LINE_BUFFER: usize = 1024;
+
+/// What to run in each checkout.
+pub struct Job {
+    pub steps: Vec<Step>,
+    /// Install the proper toolchain if it's not already present.
+    pub install: bool,
+    /// Extra environment variables for every step.
+    pub env: BTreeMap<String, String>,
+    /// Where pando was run from.
+    pub original_dir: PathBuf,
+    /// The run's ID, once it's started.
+    pub run_id: Option<String>,
+    /// Roughly how many crates a cargo step will compile, for its progress bar.
+    pub crate_count: u64,
+    /// How to log the output.
+    pub log: LogOptions,
+    /// How long all the steps in a checkout may take.
+    pub timeout: Option<Duration>,
+    /// On timeout, send SIGQUIT before SIGKILL, so processes can dump backtraces.
+    pub timeout_quit: bool,
+    /// Shared by every command, to limit how many compilers run at once across checkouts.
+    pub jobserver: Client,
+    /// If set, each toolchain's checkouts build in the same target directory.
+    pub shared_targets: Option<SharedTargets>,
+    /// If set, rustc is run through it.
+    pub compiler_cache: Option<CompilerCache>,
+    /// The named locks that steps hold, shared between checkouts.
+    pub locks: Locks,
+    /// Each checkout gets its own range of ports, starting here for the first.
+    pub port_base: usize,
+    pub ports_per_checkout: usize,
+    /// How many times to re-run a checkout's job if it fails.
+    pub retries: usize,
+    /// When retrying a failed `cargo test` with parsed results, only run the failed tests.
+    pub retry_failed_tests: bool,
+}
+
+/// A single command run in a checkout.
+pub struct Step {
+    pub name: String,
+    pub program: StepProgram,
+    /// How long this step may take.
+    pub timeout: Option<Duration>,
+    /// Named locks held while the step runs.
+    pub locks: Vec<LockSpec>,
+}
+
+pub enum StepProgram {
+    /// A cargo subcommand, e.g. `test`.
+    Cargo {
+        subcommand: String,
+        args: Vec<String>,
+    },
+    /// An arbitrary utility.
+    Each { utility: String, args: Vec<String> },
+}
+
+impl Job {
+    /// The features given to the job's cargo steps, comma separated.
+    pub fn features(&self) -> String {
+        self.cargo_args("--features", Some("-F")).join(",")
     }
-    cmd.arg(toolchain);
 
-    if cargo {
-        cmd.arg("cargo");
+    /// The target given to the job's cargo steps, if any.
+    pub fn target(&self) -> Option<String> {
+        self.cargo_args("--target", None)
+            .into_iter()
+            .next()
+            .or_else(|| self.env.get("CARGO_BUILD_TARGET").cloned())
     }
 
-    cmd.arg(utility);
-    if replacements {
-        for arg in args {
-            if arg.as_ref() == "{}" {
-                cmd.arg(toolchain);
-            } else {
-                cmd.arg(arg);
+    /// Every value of the flag given to a cargo step, e.g. `x` for `--flag x` or `--flag=x`.
+    fn cargo_args(&self, long: &str, short: Option<&str>) -> Vec<String> {
+        let mut values = Vec::new();
+        for step in &self.steps {
+            let args = match &step.program {
+                StepProgram::Cargo { args, .. } => args,
+                StepProgram::Each { .. } => continue,
+            };
+            // anything after `--` is for what cargo runs
+            let mut args = args.iter().take_while(|arg| *arg != "--");
+            while let Some(arg) = args.next() {
+                if arg == long || Some(&arg[..]) == short {
+                    values.extend(args.next().cloned());
+                } else if arg.starts_with(long) && arg[long.len()..].starts_with('=') {
+                    values.push(arg[long.len() + 1..].to_string());
+                }
             }
         }
-    } else {
-        cmd.args(args);
+        values
     }
-    cmd
 }
 
-fn command_from_action(toolchain: &str, action: &ActionOpt) -> Option<Command> {
-    let (install, cargo, replacements, util, args) = match action {
-        ActionOpt::CargoTest {
-            install,
-            test_args: args,
-            ..
-        } => (install, true, false, "test", args),
-        ActionOpt::CargoBuild {
-            install,
-            build_args: args,
-            ..
-        } => (install, true, false, "build", args),
-        ActionOpt::CargoAny {
-            install,
-            subcommand: util,
-            args,
-            ..
-        } => (install, true, false, util.as_str(), args),
-        ActionOpt::Each {
-            install,
-            utility: util,
-            args,
-            ..
-        } => (install, false, true, util.as_str(), args),
-        _ => unimplemented!()
-    };
+impl Step {
+    /// Create a step named after its cargo subcommand or utility.
+    pub fn new(program: StepProgram) -> Step {
+        let name = match &program {
+            StepProgram::Cargo { subcommand, .. } => subcommand.clone(),
+            StepProgram::Each { utility, .. } => utility.clone(),
+        };
+        Step {
+            name,
+            program,
+            timeout: None,
+            locks: Vec::new(),
+        }
+    }
 
-    Some(make_an_each_command(
-        *install,
-        toolchain,
-        cargo,
-        replacements,
-        util,
-        args,
-    ))
+    fn is_cargo_test(&self) -> bool {
+        match &self.program {
+            StepProgram::Cargo { subcommand, .. } => subcommand == "test",
+            StepProgram::Each { .. } => false,
+        }
+    }
+
+    /// Whether to ask cargo for JSON messages, to track compile progress
+    /// and collect diagnostics.
+    ///
+    /// Only done for subcommands that take `--message-format`,
+    /// when the user hasn't chosen their own.
+    fn uses_message_format(&self) -> bool {
+        match &self.program {
+            StepProgram::Cargo { subcommand, args } => {
+                MESSAGE_FORMAT_SUBCOMMANDS.contains(&subcommand.as_str())
+                    && !args.iter().any(|arg| arg.starts_with("--message-format"))
+            }
+            StepProgram::Each { .. } => false,
+        }
+    }
+
+    /// The command to run the step, with placeholders in its arguments substituted.
+    /// If `tests` are given, only those are run (for `cargo test`).
+    fn command(&self, placeholders: &Placeholders, install: bool, tests: &[String]) -> Command {
+        let mut cmd = Command::new("rustup");
+        cmd.arg("run");
+        if install {
+            cmd.arg("--install");
+        }
+        cmd.arg(&placeholders.checkout.toolchain);
+
+        match &self.program {
+            StepProgram::Cargo { subcommand, args } => {
+                cmd.arg("cargo").arg(subcommand);
+                if self.uses_message_format() {
+                    cmd.arg("--message-format=json");
+                }
+                cmd.args(args.iter().map(|arg| placeholders.substitute(arg)));
+                if !tests.is_empty() {
+                    // everything after `--` goes to the test binaries
+                    if !args.iter().any(|arg| arg == "--") {
+                        cmd.arg("--");
+                    }
+                    cmd.args(tests).arg("--exact");
+                }
+            }
+            StepProgram::Each { utility, args } => {
+                cmd.arg(placeholders.substitute(utility));
+                cmd.args(args.iter().map(|arg| placeholders.substitute(arg)));
+            }
+        }
+        cmd
+    }
 }
 
+/// What to run on an attempt at the job: everything, or only what failed last time.
+struct Rerun {
+    /// Counting from 1.
+    attempt: usize,
+    /// The index of the step to start from.
+    first_step: usize,
+    /// Only run these tests in the first step.
+    tests: Vec<String>,
+}
+
+/// Run every step of the job in the checkout, stopping at the first failure,
+/// and retrying failures as many times as the job allows.
+///
+/// Records the outcome in the checkout, and returns whether all steps succeeded.
 pub fn run_cmd<'scope, 'env: 'scope>(
     scope: &'scope Scope<'env>,
     checkout: &'env Checkout,
-    action: &'env ActionOpt,
+    job: &'env Job,
 ) -> bool {
-    match run_cmd_inner(scope, checkout, action) {
-        Err(e) => {
+    let start = Instant::now();
+    let mut rerun = Rerun {
+        attempt: 1,
+        first_step: 0,
+        tests: Vec::new(),
+    };
+
+    let mut outcome = loop {
+        let attempt_start = Instant::now();
+        let outcome = run_steps(scope, checkout, job, attempt_start, &rerun);
+        checkout.attempts.lock().unwrap().push(Attempt {
+            status: outcome.status,
+            output: attempt_log(&checkout.output, rerun.attempt),
+            duration_secs: secs(attempt_start.elapsed()),
+            failed_tests: failed_tests(checkout),
+        });
+
+        let retryable = outcome.status == Status::Failure || outcome.status == Status::TimedOut;
+        if !retryable || rerun.attempt > job.retries {
+            break outcome;
+        }
+        rerun = next_rerun(checkout, job, &outcome, rerun.attempt + 1);
+        checkout.progress.set_message(&format!(
+            "{}, retrying ({} of {})",
+            outcome.describe(&checkout.tests.lock().unwrap()),
+            rerun.attempt,
+            job.retries + 1
+        ));
+    };
+    outcome.attempt = rerun.attempt;
+    if outcome.status == Status::Success && rerun.attempt > 1 {
+        outcome.status = Status::Flaky;
+    }
+
+    let message = match outcome.status {
+        Status::Failure | Status::TimedOut => format!(
+            "{}. Check output in {}",
+            outcome.describe(&checkout.tests.lock().unwrap()),
+            attempt_log(&checkout.output, rerun.attempt).display()
+        ),
+        _ => outcome.describe(&checkout.tests.lock().unwrap()),
+    };
+    checkout.progress.finish_with_message(&message);
+
+    let success = outcome.status.is_success();
+    *checkout.outcome.lock().unwrap() = Outcome {
+        duration_secs: Some(secs(start.elapsed())),
+        ..outcome
+    };
+    success
+}
+
+fn failed_tests(checkout: &Checkout) -> Vec<String> {
+    checkout
+        .tests
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|test| test.outcome == TestOutcome::Failed)
+        .map(|test| test.name.clone())
+        .collect()
+}
+
+/// Decide what to re-run after a failed attempt.
+///
+/// If allowed, and a `cargo test` step failed only because some of its tests did,
+/// only those tests are re-run (and the steps after it).
+/// Otherwise, e.g. after a timeout or a crash, everything is re-run from scratch.
+fn next_rerun(checkout: &Checkout, job: &Job, outcome: &Outcome, attempt: usize) -> Rerun {
+    let failed = failed_tests(checkout);
+    let narrow = job.retry_failed_tests
+        && outcome.status == Status::Failure
+        && outcome.only_tests_failed
+        && !failed.is_empty();
+
+    match outcome.failed_step_index {
+        Some(i) if narrow => {
+            // the tests that passed keep their results, and the rerun adds the rest
             checkout
-                .progress
-                .finish_with_message(&format!("failure: {}", e));
-            false
-        }
-        Ok(status) => match (status.success(), status.code()) {
-            (true, _) => {
-                checkout.progress.finish_with_message("success");
-                true
-            }
-            (false, Some(code)) => {
-                checkout.progress.finish_with_message(&format!(
-                    "failure: status {}. Check output in {}",
-                    code,
-                    checkout.output.display()
-                ));
-                false
+                .tests
+                .lock()
+                .unwrap()
+                .retain(|test| test.outcome != TestOutcome::Failed);
+            Rerun {
+                attempt,
+                first_step: i,
+                tests: failed,
             }
-            (false, None) => {
-                checkout.progress.finish_with_message(&format!(
-                    "failure: status unknown. Check output in {}",
-                    checkout.output.display()
-                ));
-                false
+        }
+        _ => {
+            checkout.tests.lock().unwrap().clear();
+            *checkout.diagnostics.lock().unwrap() = None;
+            Rerun {
+                attempt,
+                first_step: 0,
+                tests: Vec::new(),
             }
-        },
+        }
     }
 }
 
-fn run_cmd_inner<'scope, 'env: 'scope>(
+fn run_steps<'scope, 'env: 'scope>(
     scope: &'scope Scope<'env>,
     checkout: &'env Checkout,
-    action: &'env ActionOpt,
-) -> Result<ExitStatus, Error> {
-    let mut file = File::create(&checkout.output)
-        .with_context(|_| format!("error creating output file {}", checkout.output.display()))?;
+    job: &'env Job,
+    start: Instant,
+    rerun: &Rerun,
+) -> Outcome {
+    checkout.progress.set_message("getting rustc version");
+    let mut outcome = Outcome {
+        rustc_version: get_rustc_version(&checkout.toolchain, job.install).ok(),
+        ..Outcome::default()
+    };
 
-    checkout.progress.inc(1);
-    checkout.progress.set_message("testing");
+    let logs = Logs::create(
+        &attempt_log(&checkout.output, rerun.attempt),
+        &attempt_log(&checkout.stdout_log, rerun.attempt),
+        &attempt_log(&checkout.stderr_log, rerun.attempt),
+    );
+    let mut logs = match logs {
+        Ok(logs) => logs,
+        Err(e) => {
+            outcome.status = Status::Error;
+            outcome.error = Some(e.to_string());
+            return outcome;
+        }
+    };
 
-    let mut cmd = command_from_action(&checkout.toolchain, action).unwrap();
+    for (i, step) in job.steps.iter().enumerate().skip(rerun.first_step) {
+        if cancel::is_cancelled() || checkout.stop.load(Ordering::SeqCst) {
+            outcome.status = Status::Cancelled;
+            return outcome;
+        }
+
+        // whichever comes first of the checkout's and the step's timeouts,
+        // ignoring any so long that their deadline can't be represented
+        let limit = [
+            job.timeout.and_then(|timeout| {
+                start
+                    .checked_add(timeout)
+                    .map(|deadline| (deadline, timeout))
+            }),
+            step.timeout.and_then(|timeout| {
+                Instant::now()
+                    .checked_add(timeout)
+                    .map(|deadline| (deadline, timeout))
+            }),
+        ]
+        .iter()
+        .flatten()
+        .min_by_key(|(deadline, _)| *deadline)
+        .cloned();
+
+        let tests = if i == rerun.first_step {
+            &rerun.tests[..]
+        } else {
+            &[]
+        };
+
+        match run_step(scope, checkout, job, step, tests, &mut logs, start, limit) {
+            Ok(Exit::Status { status, .. }) if status.success() => {}
+            Ok(Exit::TimedOut(timeout)) => {
+                outcome.status = Status::TimedOut;
+                outcome.timeout_secs = Some(secs(timeout));
+                outcome.failed_step = Some(step.name.clone());
+                outcome.failed_step_index = Some(i);
+                return outcome;
+            }
+            Ok(Exit::Cancelled) => {
+                outcome.status = Status::Cancelled;
+                outcome.failed_step = Some(step.name.clone());
+                outcome.failed_step_index = Some(i);
+                return outcome;
+            }
+            Ok(Exit::Status {
+                status,
+                tests_crashed,
+            }) => {
+                outcome.status = Status::Failure;
+                outcome.exit_code = status.code();
+                outcome.signal = signal(status);
+                outcome.failed_step = Some(step.name.clone());
+                outcome.failed_step_index = Some(i);
+                // cargo exits with 101 when tests fail, passing on libtest's exit code
+                outcome.only_tests_failed =
+                    step.is_cargo_test() && status.code() == Some(101) && !tests_crashed;
+                return outcome;
+            }
+            Err(e) => {
+                outcome.status = Status::Error;
+                outcome.error = Some(e.to_string());
+                outcome.failed_step = Some(step.name.clone());
+                outcome.failed_step_index = Some(i);
+                return outcome;
+            }
+        }
+    }
+
+    outcome.status = Status::Success;
+    outcome
+}
+
+/// Tell the step about the checkout it's running in, through `PANDO_*` env vars.
+///
+/// Every one is set, even if empty, so scripts can rely on them.
+fn set_checkout_env(cmd: &mut Command, checkout: &Checkout, job: &Job, progress: &ProgressFile) {
+    let port_base = job.port_base + checkout.index * job.ports_per_checkout;
+    let rev = checkout.rev.as_ref().map(|rev| &rev.id[..]);
+    cmd.env("PANDO_TOOLCHAIN", &checkout.toolchain)
+        .env("PANDO_REV", rev.unwrap_or_default())
+        .env("PANDO_FEATURES", job.features())
+        .env("PANDO_TARGET", job.target().unwrap_or_default())
+        .env("PANDO_CHECKOUT_DIR", &checkout.working_dir)
+        .env("PANDO_ORIGINAL_DIR", &job.original_dir)
+        .env(
+            "PANDO_RUN_ID",
+            job.run_id.as_ref().map_or("", String::as_str),
+        )
+        .env("PANDO_PROGRESS", progress.path())
+        .env("PANDO_INDEX", checkout.index.to_string())
+        .env("PANDO_PORT_BASE", port_base.to_string())
+        .env("PANDO_PORT_COUNT", job.ports_per_checkout.to_string());
+}
+
+#[cfg(unix)]
+fn signal(status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn signal(_status: ExitStatus) -> Option<i32> {
+    None
+}
+
+/// How a step's process ended.
+enum Exit {
+    Status {
+        status: ExitStatus,
+        /// Whether cargo said a test binary exited abnormally, e.g. crashed.
+        tests_crashed: bool,
+    },
+    /// Killed for taking longer than this.
+    TimedOut(Duration),
+    /// Terminated because the run was cancelled.
+    Cancelled,
+}
+
+/// Run a single step, killing it if it's still running at the deadline of `limit`.
+#[allow(clippy::too_many_arguments)]
+fn run_step<'scope, 'env: 'scope>(
+    scope: &'scope Scope<'env>,
+    checkout: &'env Checkout,
+    job: &'env Job,
+    step: &'env Step,
+    tests: &[String],
+    logs: &mut Logs,
+    start: Instant,
+    limit: Option<(Instant, Duration)>,
+) -> Result<Exit, Error> {
+    let message_format = step.uses_message_format();
+    if message_format {
+        checkout
+            .diagnostics
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Diagnostics::new);
+    }
 
+    // With JSON messages, progress is crates compiled out of the crates in the graph.
+    // Otherwise, it's just whether the step is done.
+    let crate_count = if message_format {
+        job.crate_count.max(1)
+    } else {
+        1
+    };
+    checkout.progress.set_position(0);
+    checkout.progress.set_length(crate_count);
+    checkout.progress.set_message(&step.name);
+
+    let placeholders = Placeholders { checkout, job };
+    let mut cmd = step.command(&placeholders, job.install, tests);
+    process::own_process_group(&mut cmd);
+    job.jobserver.configure_make(&mut cmd);
+    if let Some(cache) = &job.compiler_cache {
+        cmd.env("RUSTC_WRAPPER", &cache.wrapper);
+    }
+    let shared_target = job.shared_targets.as_ref().map(|shared| {
+        cmd.env("CARGO_TARGET_DIR", shared.dir(&checkout.toolchain));
+        shared.lock(&checkout.toolchain)
+    });
+    let _shared_target = shared_target.as_ref().map(|lock| {
+        checkout
+            .progress
+            .set_message(&format!("{}: waiting for the shared target dir", step.name));
+        let guard = lock.lock().unwrap();
+        checkout.progress.set_message(&step.name);
+        guard
+    });
+    let _locks = if step.locks.is_empty() {
+        Vec::new()
+    } else {
+        let names = step.locks.iter().map(|lock| &lock.name[..]);
+        checkout.progress.set_message(&format!(
+            "{}: waiting for {}",
+            step.name,
+            names.collect::<Vec<_>>().join(", ")
+        ));
+        let permits = job.locks.acquire(&step.locks);
+        checkout.progress.set_message(&step.name);
+        permits
+    };
+    let mut progress = ProgressFile::create(&checkout.progress_file)?;
+    set_checkout_env(&mut cmd, checkout, job, &progress);
     let mut child = cmd
+        .envs(&job.env)
         .current_dir(&checkout.working_dir)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
-        .with_context(|_| format!("error spawning cargo +{} test", checkout.toolchain))?;
+        .with_context(|_| format!("error spawning {} with {}", step.name, checkout.toolchain))?;
+    let group = cancel::track_group(child.id());
 
     checkout.progress.enable_steady_tick(500); // ms
 
-    let (lines_tx, lines_rx) = unbounded::<String>();
+    // bounded, so a chatty step can't fill memory faster than it can be logged
+    let (lines_tx, lines_rx) = bounded::<OutputLine>(LINE_BUFFER);
+    let line = move |stream, bytes| OutputLine {
+        stream,
+        elapsed: start.elapsed(),
+        bytes,
+    };
 
     let stdout = child.stdout.take().unwrap();
     let stdout_tx = lines_tx.clone();
     scope.spawn(move |_| {
-        for line in BufReader::new(stdout).lines() {
-            stdout_tx.send(line.unwrap()).unwrap();
+        let mut compiled = 0;
+        let mut total = crate_count;
+        let result = read_lines(BufReader::new(stdout), |bytes| {
+            let text = logs::text(&bytes);
+            if message_format {
+                match Message::parse(&text) {
+                    Some(Message::CompilerArtifact) => {
+                        compiled += 1;
+                        // the dependency count is only an estimate
+                        if compiled >= total {
+                            total = compiled + 1;
+                            checkout.progress.set_length(total);
+                        }
+                        checkout.progress.set_position(compiled);
+                        return;
+                    }
+                    Some(Message::Diagnostic { message }) => {
+                        let diagnostic = message.to_diagnostic();
+                        let rendered = match (message.rendered, &diagnostic) {
+                            (Some(rendered), _) => rendered,
+                            (None, Some(diagnostic)) => diagnostic.to_string(),
+                            (None, None) => return,
+                        };
+                        let rendered = rendered.trim_end().to_string();
+
+                        if let Some(first_line) = rendered.lines().next() {
+                            checkout
+                                .progress
+                                .set_message(&format!("{}: {}", step.name, first_line));
+                        }
+                        if let Some(diagnostic) = diagnostic {
+                            if let Some(diagnostics) = &mut *checkout.diagnostics.lock().unwrap() {
+                                diagnostics.insert(diagnostic, rendered.clone());
+                            }
+                        }
+                        // cargo doesn't print these itself when giving JSON,
+                        // but they'd normally be on stderr
+                        let bytes = format!("{}\n", rendered).into_bytes();
+                        let _ = stdout_tx.send(line(Stream::Stderr, bytes));
+                        return;
+                    }
+                    Some(Message::BuildFinished) => {
+                        checkout.progress.set_length(compiled);
+                        return;
+                    }
+                    Some(Message::Other) => return,
+                    None => {}
+                }
+            }
+            if let Some(result) = libtest::parse_line(&text) {
+                checkout.tests.lock().unwrap().push(result);
+            }
+            let _ = stdout_tx.send(line(Stream::Stdout, bytes));
+        });
+        if let Err(e) = result {
+            let error = format!("[pando: error reading stdout: {}]\n", e);
+            let _ = stdout_tx.send(line(Stream::Stderr, error.into_bytes()));
         }
     });
 
     let stderr = child.stderr.take().unwrap();
     let stderr_tx = lines_tx;
     scope.spawn(move |_| {
-        for line in BufReader::new(stderr).lines() {
-            let line = line.unwrap();
-            let trimmed = line.trim();
+        let result = read_lines(BufReader::new(stderr), |bytes| {
+            let text = logs::text(&bytes);
+            let trimmed = text.trim();
             if !trimmed.is_empty() {
                 checkout
                     .progress
-                    .set_message(&format!("testing: {}", trimmed));
+                    .set_message(&format!("{}: {}", step.name, trimmed));
             }
-            stderr_tx.send(line).unwrap();
+            let _ = stderr_tx.send(line(Stream::Stderr, bytes));
+        });
+        if let Err(e) = result {
+            let error = format!("[pando: error reading stderr: {}]\n", e);
+            let _ = stderr_tx.send(line(Stream::Stderr, error.into_bytes()));
         }
     });
 
-    for line in lines_rx {
-        file.write_all(line.as_bytes())?;
-        file.write_all(b"\n")?;
+    let mut supervisor = Supervisor::new(
+        limit.map(|(deadline, _)| deadline),
+        job.timeout_quit,
+        &checkout.stop,
+    );
+    let mut progress_read = Instant::now();
+    let mut tests_crashed = false;
+    let mut write_error = None;
+    loop {
+        match lines_rx.recv_timeout(supervisor.poll_interval()) {
+            // once logging fails, the rest is drained so the readers can finish
+            Ok(_) if write_error.is_some() => {}
+            Ok(line) => {
+                if step.is_cargo_test() && libtest::is_abnormal_exit(&logs::text(&line.bytes)) {
+                    tests_crashed = true;
+                }
+                if let Err(e) = logs.write(&line, &job.log) {
+                    // the step can't be left running unsupervised
+                    supervisor.kill(&mut child);
+                    write_error = Some(e);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        // checked after every line too, since a chatty process never lets recv time out
+        let was_stopped = supervisor.stopped();
+        supervisor.check(&mut child);
+        match supervisor.stopped() {
+            Some(stopped) if was_stopped.is_none() => {
+                let reason = match stopped {
+                    Stopped::TimedOut => "timed out",
+                    Stopped::Cancelled => "cancelling",
+                };
+                checkout
+                    .progress
+                    .set_message(&format!("{}: {}", step.name, reason));
+            }
+            _ => {}
+        }
+
+        if progress_read.elapsed() >= process::POLL_INTERVAL {
+            progress_read = Instant::now();
+            progress.read_new(|line| match Update::parse(line) {
+                Update::Stage {
+                    stage,
+                    stages,
+                    message,
+                } => {
+                    checkout.progress.set_length(stages);
+                    checkout.progress.set_position(stage);
+                    if !message.is_empty() {
+                        checkout
+                            .progress
+                            .set_message(&format!("{}: {}", step.name, message));
+                    }
+                }
+                Update::Message(message) => checkout
+                    .progress
+                    .set_message(&format!("{}: {}", step.name, message)),
+            });
+        }
     }
 
-    Ok(child.wait()?)
+    let status = supervisor.wait(&mut child)?;
+    drop(group);
+    if let Some(e) = write_error {
+        return Err(e).context("error writing the log")?;
+    }
+    match (supervisor.stopped(), limit) {
+        (Some(Stopped::TimedOut), Some((_, limit))) => Ok(Exit::TimedOut(limit)),
+        (Some(Stopped::Cancelled), _) => Ok(Exit::Cancelled),
+        _ => Ok(Exit::Status {
+            status,
+            tests_crashed,
+        }),
+    }
 }
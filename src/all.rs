@@ -0,0 +1,83 @@
+//! Running one command with every checkout at once, for the `all` action.
+use super::git::Rev;
+use super::placeholders;
+use super::Checkout;
+use failure::{Error, ResultExt};
+use serde_derive::Serialize;
+use std::fs::File;
+use std::path::Path;
+use std::process::Command;
+
+/// Where the manifest of checkouts is written, within `target/pando`.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// A checkout, as described in the manifest.
+#[derive(Serialize)]
+struct ManifestEntry<'a> {
+    name: String,
+    toolchain: &'a str,
+    rev: Option<&'a Rev>,
+    dir: &'a Path,
+}
+
+/// Write a JSON array describing every checkout to `path`.
+pub fn write_manifest(checkouts: &[Checkout], path: &Path) -> Result<(), Error> {
+    let entries = checkouts
+        .iter()
+        .map(|checkout| ManifestEntry {
+            name: checkout.name(),
+            toolchain: &checkout.toolchain,
+            rev: checkout.rev.as_ref(),
+            dir: &checkout.working_dir,
+        })
+        .collect::<Vec<_>>();
+
+    let file =
+        File::create(path).with_context(|_| format!("could not create {}", path.display()))?;
+    serde_json::to_writer_pretty(file, &entries)
+        .with_context(|_| format!("could not write {}", path.display()))?;
+    Ok(())
+}
+
+/// The command to run, with every checkout filled in.
+///
+/// An argument that's just `{dirs}` becomes the checkouts' directories, one argument each,
+/// and `{toolchains}` their toolchains, in the same order.
+/// `{manifest}` anywhere becomes the path of the manifest, and `{{` and `}}` are literal braces.
+/// If none of those are given, the directories are added to the end.
+pub fn command(utility: &str, args: &[String], checkouts: &[Checkout], manifest: &Path) -> Command {
+    let dirs = checkouts
+        .iter()
+        .map(|checkout| checkout.working_dir.as_os_str());
+    let toolchains = checkouts.iter().map(|checkout| &checkout.toolchain);
+
+    let mut cmd = Command::new(utility);
+    cmd.env("PANDO_MANIFEST", manifest);
+
+    let mut placed = false;
+    for arg in args {
+        match &arg[..] {
+            "{dirs}" => {
+                cmd.args(dirs.clone());
+                placed = true;
+            }
+            "{toolchains}" => {
+                cmd.args(toolchains.clone());
+                placed = true;
+            }
+            _ => {
+                cmd.arg(placeholders::substitute(arg, |name| match name {
+                    "manifest" => {
+                        placed = true;
+                        Some(manifest.display().to_string())
+                    }
+                    _ => None,
+                }));
+            }
+        }
+    }
+    if !placed {
+        cmd.args(dirs);
+    }
+    cmd
+}
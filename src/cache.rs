@@ -0,0 +1,109 @@
+//! Sharing build work between checkouts:
+//! a target directory per toolchain, and compiler caches like sccache.
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+/// Where the shared target directories are kept, within `target/pando`.
+const SHARED_DIR: &str = "shared-target";
+
+/// A target directory for each toolchain, shared by its checkouts of every revision,
+/// so dependencies are only built once per toolchain.
+///
+/// Only one step at a time uses each, so their cargos don't fight over it.
+pub struct SharedTargets {
+    dir: PathBuf,
+    locks: Mutex<BTreeMap<String, Arc<Mutex<()>>>>,
+}
+
+impl SharedTargets {
+    pub fn new(pando_dir: &Path) -> SharedTargets {
+        SharedTargets {
+            dir: pando_dir.join(SHARED_DIR),
+            locks: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The target directory for the toolchain.
+    pub fn dir(&self, toolchain: &str) -> PathBuf {
+        self.dir.join(toolchain)
+    }
+
+    /// The lock to hold while using the toolchain's target directory.
+    pub fn lock(&self, toolchain: &str) -> Arc<Mutex<()>> {
+        self.locks
+            .lock()
+            .unwrap()
+            .entry(toolchain.to_string())
+            .or_default()
+            .clone()
+    }
+}
+
+/// A compiler cache that rustc is run through, with `RUSTC_WRAPPER`.
+pub struct CompilerCache {
+    pub wrapper: String,
+}
+
+impl CompilerCache {
+    /// Its total cache hits and misses so far, if it can say. Only sccache can.
+    pub fn stats(&self) -> Option<CacheStats> {
+        let name = Path::new(&self.wrapper).file_stem()?;
+        if name != "sccache" {
+            return None;
+        }
+
+        let output = Command::new(&self.wrapper)
+            .arg("--show-stats")
+            .output()
+            .ok()?;
+        let output = String::from_utf8_lossy(&output.stdout);
+        // e.g. `Cache hits                           12`,
+        // leaving out the per-language lines like `Cache hits (Rust)    12`
+        let count = |label: &str| {
+            output
+                .lines()
+                .filter(|line| line.starts_with(label))
+                .find_map(|line| line[label.len()..].trim().parse::<u64>().ok())
+        };
+        Some(CacheStats {
+            hits: count("Cache hits")?,
+            misses: count("Cache misses")?,
+        })
+    }
+}
+
+/// How often a compiler cache was hit, e.g. during a run.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// The hits and misses since `before`.
+    pub fn since(self, before: CacheStats) -> CacheStats {
+        CacheStats {
+            hits: self.hits.saturating_sub(before.hits),
+            misses: self.misses.saturating_sub(before.misses),
+        }
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} hits, {} misses", self.hits, self.misses)?;
+        let total = self.hits + self.misses;
+        if total > 0 {
+            write!(
+                f,
+                " ({:.0}% hit rate)",
+                self.hits as f64 * 100.0 / total as f64
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+//! Cancelling a run with Ctrl-C.
+//!
+//! The first Ctrl-C (or SIGTERM) asks the run to stop: no more checkouts are started,
+//! and running ones are terminated. A second one kills them and exits immediately.
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: AtomicI32 = AtomicI32::new(0);
+
+/// Sixteen of the given expression, as an array.
+/// (`[EMPTY; 256]` needs Rust 1.38, since atomics aren't `Copy`.)
+macro_rules! sixteen {
+    ($e:expr) => {
+        [
+            $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e,
+        ]
+    };
+}
+
+/// The process groups of running steps, or 0 for an empty slot, in 256 slots,
+/// which is more than a run has workers. A fixed array of atomics,
+/// so the signal handler can read it without locking or allocating.
+static GROUPS: [[AtomicI32; 16]; 16] = sixteen!(sixteen!(EMPTY));
+
+fn group_slots() -> impl Iterator<Item = &'static AtomicI32> {
+    GROUPS.iter().flat_map(|row| row.iter())
+}
+
+/// A process group that's killed if pando exits on a second Ctrl-C,
+/// since being in its own group means it won't get the Ctrl-C itself.
+/// Untracked when dropped, which should be as soon as the process has been waited on.
+pub struct TrackedGroup(Option<usize>);
+
+/// Track the process group led by `pid`. If every slot is taken, it just isn't tracked.
+pub fn track_group(pid: u32) -> TrackedGroup {
+    let slot = group_slots().position(|slot| {
+        slot.compare_exchange(0, pid as i32, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    });
+    TrackedGroup(slot)
+}
+
+impl Drop for TrackedGroup {
+    fn drop(&mut self) {
+        if let Some(slot) = self.0 {
+            GROUPS[slot / 16][slot % 16].store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Whether the run has been cancelled.
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+#[cfg(unix)]
+pub fn install_handler() {
+    extern "C" fn handle(_signal: std::os::raw::c_int) {
+        if CANCELLED.swap(true, Ordering::SeqCst) {
+            // only async-signal-safe calls in here
+            for slot in group_slots() {
+                let pgid = slot.load(Ordering::SeqCst);
+                if pgid > 0 {
+                    unsafe { libc::kill(-pgid, libc::SIGKILL) };
+                }
+            }
+            unsafe { libc::_exit(130) }
+        }
+    }
+
+    let handler = handle as extern "C" fn(std::os::raw::c_int);
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install_handler() {}
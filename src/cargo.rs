@@ -1,24 +1,96 @@
 //! Read applicable metadata from cargo.
+use super::diagnostics::RustcDiagnostic;
+use serde::de::IgnoredAny;
 use serde_derive::Deserialize;
-use serde_yaml::from_reader;
-use std::env::var_os;
-use std::process::{Command, Stdio};
+use serde_yaml::{from_reader, Value};
+use std::env::{current_dir, var_os};
 use std::ffi::OsString;
+use std::path::Path;
+use std::process::{Command, Stdio};
 
 /// Metadata obtained from cargo.
 #[derive(Deserialize, Debug)]
 pub struct CargoMetadata {
     pub target_directory: String,
+    pub workspace_root: String,
+    pub packages: Vec<Package>,
+    /// The `[workspace.metadata]` table.
+    #[serde(default)]
+    pub metadata: Value,
+    pub resolve: Option<Resolve>,
+}
+
+/// The resolved dependency graph.
+#[derive(Deserialize, Debug)]
+pub struct Resolve {
+    nodes: Vec<IgnoredAny>,
+}
+
+/// A single package from cargo metadata.
+#[derive(Deserialize, Debug)]
+pub struct Package {
+    pub manifest_path: String,
+    /// The `[package.metadata]` table.
+    #[serde(default)]
+    pub metadata: Value,
+}
+
+impl CargoMetadata {
+    /// The package whose manifest is in the current directory, if any.
+    pub fn current_package(&self) -> Option<&Package> {
+        let manifest = current_dir().ok()?.join("Cargo.toml");
+        self.packages
+            .iter()
+            .find(|package| Path::new(&package.manifest_path) == manifest)
+    }
+
+    /// The number of crates in the dependency graph,
+    /// an estimate of how many a build will compile.
+    pub fn crate_count(&self) -> u64 {
+        self.resolve
+            .as_ref()
+            .map_or(0, |resolve| resolve.nodes.len() as u64)
+    }
+}
+
+/// The subcommands that accept `--message-format`.
+pub const MESSAGE_FORMAT_SUBCOMMANDS: &[&str] = &[
+    "bench", "build", "check", "clippy", "doc", "rustc", "rustdoc", "test",
+];
+
+/// A message from cargo's `--message-format=json` output.
+///
+/// Only the messages pando cares about are parsed.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum Message {
+    CompilerArtifact,
+    #[serde(rename = "compiler-message")]
+    Diagnostic {
+        message: RustcDiagnostic,
+    },
+    BuildFinished,
+    #[serde(other)]
+    Other,
+}
+
+impl Message {
+    /// Parse a line of stdout, if it's a cargo message.
+    pub fn parse(line: &str) -> Option<Message> {
+        if !line.starts_with('{') {
+            return None;
+        }
+        serde_json::from_str(line).ok()
+    }
 }
 
 /// Retrieves metadata via `cargo metadata`. Respects the `CARGO` env var.
 pub fn get_cargo_metadata() -> CargoMetadata {
-    let mut child = Command::new(var_os("CARGO").unwrap_or(OsString::from("cargo")))
+    let output = Command::new(var_os("CARGO").unwrap_or_else(|| OsString::from("cargo")))
         .args(&["metadata", "--format-version", "1"])
-        .stdout(Stdio::piped())
         .stderr(Stdio::null())
-        .spawn()
+        .output()
         .unwrap();
 
-    from_reader(child.stdout.take().unwrap()).unwrap()
+    from_reader(&output.stdout[..]).unwrap()
 }
@@ -1,15 +1,14 @@
+use super::locks::LockSpec;
 use ::structopt::*;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 
 /// Perform tasks concurrently over multiple copies of your repo.
-/// 
+///
 /// See the help for the various subcommands for details.
-/// 
-/// Note that there is no subcommand to execute _one_ command with each checkout.
-/// For that, use print, cut and xargs:
-/// 
-/// # echoes all toolchains on one line instead of each on a separate line
-/// 
-/// cargo pando print | cut -f 1 | xargs echo
+///
+/// To execute _one_ command with every checkout, use all.
 #[derive(StructOpt, Debug)]
 #[structopt(name = "cargo pando")]
 pub struct Opts {
@@ -40,11 +39,203 @@ pub struct Opts {
     /// Use all installed toolchains except for the current default.
     ///
     /// Mutually exclusive with --toolchain.
-    #[structopt(short, long, conflicts_with = "toolchain")]
+    // named apart from the all subcommand, which clap would otherwise count as this flag
+    #[structopt(
+        name = "all-toolchains",
+        short,
+        long = "all",
+        conflicts_with = "toolchain"
+    )]
     pub all: bool,
 
+    /// Check out the given git revision for each toolchain. May be given multiple times.
+    ///
+    /// Every toolchain is run against every revision.
+    /// Mutually exclusive with --index and --copy.
+    #[structopt(
+        short,
+        long,
+        number_of_values = 1,
+        conflicts_with = "index",
+        conflicts_with = "copy"
+    )]
+    pub rev: Vec<String>,
+
+    /// Use the named preset from the pando config.
+    ///
+    /// The config is read from pando.toml in the workspace root,
+    /// or else [package.metadata.pando] or [workspace.metadata.pando] in Cargo.toml.
+    /// Flags given on the command line override the preset's values.
+    /// If the preset names a pipeline, the subcommand may be omitted.
+    #[structopt(long)]
+    pub preset: Option<String>,
+
+    /// Set the environment variables from the named env set in the pando config.
+    ///
+    /// May be given multiple times. Applied after those of the preset.
+    #[structopt(long, number_of_values = 1)]
+    pub env_set: Vec<String>,
+
+    /// After running, print compiler diagnostics deduplicated across toolchains.
+    ///
+    /// Diagnostics that only appear on some toolchains are listed last and highlighted.
+    /// Only collected from cargo subcommands that compile, like check, build and clippy.
+    #[structopt(long)]
+    pub diagnostics: bool,
+
+    /// After running, write the deduplicated compiler diagnostics as JSON to this path.
+    #[structopt(long, parse(from_os_str))]
+    pub diagnostics_json: Option<PathBuf>,
+
+    /// After running, write the results as JUnit XML to this path.
+    ///
+    /// Each checkout is a testsuite, with a testcase per test parsed from the output.
+    /// If no tests could be parsed, the checkout is a single testcase with its log.
+    #[structopt(long, parse(from_os_str))]
+    pub junit: Option<PathBuf>,
+
+    /// How to print the results after running: human, json, markdown or tap.
+    ///
+    /// json prints a summary of every checkout to stdout.
+    /// markdown prints a table of results, with the end of each failing log,
+    /// for pasting into pull requests.
+    /// tap prints a TAP test point per checkout, with the end of each failing log
+    /// as diagnostics.
+    #[structopt(long, default_value = "human", possible_values = OutputFormat::VARIANTS)]
+    pub format: OutputFormat,
+
+    /// Print a report of the results in this format: markdown.
+    ///
+    /// markdown is a table of results, with the end of each failing log,
+    /// for pasting into pull requests. The same as --format markdown.
+    #[structopt(long, possible_values = ReportFormat::VARIANTS)]
+    pub report: Option<ReportFormat>,
+
+    /// After running, write a JSON summary of every checkout to this path.
+    #[structopt(long, parse(from_os_str))]
+    pub summary_json: Option<PathBuf>,
+
+    /// With --format tap, make each parsed test a test point instead of each checkout.
+    ///
+    /// Checkouts without parsed tests are still a single test point.
+    #[structopt(long)]
+    pub tap_tests: bool,
+
+    /// With --format tap, how many lines of a failing log to include as diagnostics.
+    #[structopt(long, default_value = "20")]
+    pub tap_log_lines: usize,
+
+    /// Prefix each line of the logs with the time since the checkout started running.
+    #[structopt(long)]
+    pub log_elapsed: bool,
+
+    /// Prefix each line of the combined log with the stream it came from,
+    /// `out|` or `err|`.
+    ///
+    /// stdout and stderr are always logged separately too.
+    #[structopt(long)]
+    pub log_streams: bool,
+
+    /// Once a log reaches this size, rotate it, or cut it off if --log-rotations is 0.
+    ///
+    /// In bytes, or with a K, M or G suffix, e.g. 10M.
+    #[structopt(long, parse(try_from_str = parse_size))]
+    pub max_log_size: Option<u64>,
+
+    /// With --max-log-size, how many rotated logs to keep, e.g. stable.log.1.
+    #[structopt(long, default_value = "0")]
+    pub log_rotations: usize,
+
+    /// Kill a checkout's commands if they run for longer than this, e.g. 30s, 10m or 1h.
+    ///
+    /// The whole process group is killed, and the checkout is marked as timed out.
+    /// Steps of a pipeline can have their own timeouts in the pando config.
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub timeout: Option<Duration>,
+
+    /// On timeout, send SIGQUIT and wait a few seconds before sending SIGKILL,
+    /// so hung processes can dump backtraces.
+    #[structopt(long)]
+    pub timeout_quit: bool,
+
+    /// Re-run a checkout's job up to this many times if it fails or times out.
+    ///
+    /// Each attempt is logged separately, e.g. stable.attempt2.log.
+    /// Checkouts that pass on a retry are reported as flaky.
+    #[structopt(long, default_value = "0")]
+    pub retries: usize,
+
+    /// With --retries, only re-run the tests that failed, when they could be parsed.
+    #[structopt(long)]
+    pub retry_failed_tests: bool,
+
+    /// Stop as soon as a checkout fails: running checkouts are cancelled,
+    /// and pending ones are skipped.
+    ///
+    /// With --fail-fast=per-dimension, only the other checkouts of the failing
+    /// checkout's toolchain are stopped, e.g. its other revisions.
+    #[structopt(long, require_equals = true, possible_values = FailFast::VARIANTS)]
+    pub fail_fast: Option<Option<FailFast>>,
+
+    /// How many compiler processes may run at once, across all checkouts. Defaults to the number of CPUs.
+    ///
+    /// Every command is given the same GNU make jobserver (through CARGO_MAKEFLAGS and MAKEFLAGS),
+    /// so the checkouts' cargos share its tokens, rather than each running a job per CPU.
+    #[structopt(long)]
+    pub build_jobs: Option<usize>,
+
+    /// Share a target directory between the checkouts of each toolchain,
+    /// so dependencies are built once per toolchain rather than once per revision.
+    ///
+    /// Only one step at a time uses each toolchain's target directory.
+    #[structopt(long)]
+    pub shared_target_dir: bool,
+
+    /// Run rustc through this compiler cache, e.g. sccache, by setting RUSTC_WRAPPER.
+    ///
+    /// With sccache, its hit rate over the run is reported at the end.
+    #[structopt(long)]
+    pub rustc_wrapper: Option<String>,
+
+    /// Hold this named lock while running each step, e.g. for a database the tests share.
+    ///
+    /// Give a count to let that many steps hold it at once, e.g. db=2.
+    #[structopt(long, number_of_values = 1)]
+    pub lock: Vec<LockSpec>,
+
+    /// The first port of the range given to the first checkout, as PANDO_PORT_BASE.
+    ///
+    /// Each checkout gets its own range, so tests can listen without colliding.
+    #[structopt(long, default_value = "20000")]
+    pub port_base: usize,
+
+    /// How many ports are in each checkout's range, as PANDO_PORT_COUNT.
+    #[structopt(long, default_value = "100")]
+    pub ports_per_checkout: usize,
+
+    /// Only start another checkout while the 1 minute load average is at most this.
+    #[structopt(long)]
+    pub max_load: Option<f64>,
+
+    /// Only start another checkout while at least this much memory is available,
+    /// e.g. 4G, so linking in many checkouts at once doesn't run out.
+    ///
+    /// In bytes, or with a K, M or G suffix.
+    #[structopt(long, parse(try_from_str = parse_size))]
+    pub min_free_memory: Option<u64>,
+
+    /// How many past runs to keep in target/pando/runs. Defaults to 10.
+    #[structopt(long)]
+    pub keep_runs: Option<usize>,
+
+    /// Don't show progress bars.
+    ///
+    /// They're also hidden when stderr isn't a terminal.
+    #[structopt(long)]
+    pub no_progress: bool,
+
     #[structopt(subcommand)]
-    pub action: ActionOpt,
+    pub action: Option<ActionOpt>,
 }
 
 #[derive(StructOpt, Debug)]
@@ -61,7 +252,7 @@ pub enum ActionOpt {
         #[structopt(short, long)]
         jobs: Option<usize>,
 
-        /// Arguments passed along to cargo test.
+        /// Arguments passed along to cargo test, with placeholders like {toolchain} replaced.
         test_args: Vec<String>,
     },
 
@@ -76,7 +267,7 @@ pub enum ActionOpt {
         #[structopt(short, long)]
         jobs: Option<usize>,
 
-        /// Arguments passed along to cargo build.
+        /// Arguments passed along to cargo build, with placeholders like {toolchain} replaced.
         build_args: Vec<String>,
     },
 
@@ -92,14 +283,17 @@ pub enum ActionOpt {
         jobs: Option<usize>,
 
         subcommand: String,
-        /// Arguments passed along to cargo.
+        /// Arguments passed along to cargo, with placeholders like {toolchain} replaced.
         args: Vec<String>,
     },
 
     /// Execute the given command once per checkout.
     ///
     /// The directory will be changed to the checkout dir.
-    /// Any argument named ``{}`` will be replaced by the toolchain version.
+    /// Placeholders anywhere in the arguments are replaced:
+    /// {toolchain} (or just {}), {rev}, {short_rev}, {target}, {features},
+    /// {dir}, {orig_dir} and {index}. Use {{ and }} for literal braces.
+    /// The same goes for the arguments of test, build and cargo.
     #[structopt(name = "each")]
     Each {
         /// Install the proper toolchain if it's not already present.
@@ -114,42 +308,280 @@ pub enum ActionOpt {
         args: Vec<String>,
     },
 
+    /// Prepare every checkout, then execute the given command once, with all of them.
+    ///
+    /// The command is run from the current directory, and is given the checkouts' directories
+    /// at the end of its arguments. Or, place them yourself:
+    /// an argument that's just {dirs} becomes the directories, one argument each,
+    /// and {toolchains} becomes their toolchains, in the same order.
+    /// {manifest} becomes the path of a JSON file describing every checkout,
+    /// which is also given as PANDO_MANIFEST.
+    ///
+    /// # diff what each toolchain generated
+    ///
+    /// cargo pando -t stable -t nightly all -- diff -r {dirs}
+    #[structopt(name = "all")]
+    All { utility: String, args: Vec<String> },
+
     /// Copy and do nothing but print the full path of each checkout, one per line.
-    /// 
+    ///
     /// Serves as a useful starting point to run a command across _all_ checkouts at once.
-    /// 
+    ///
     /// # echoes all toolchains on one line instead of each on a separate line
-    /// 
+    ///
     /// cargo pando print | cut -f 1 | xargs echo
     #[structopt(name = "print")]
-    Print,
+    Print {
+        /// How to print each checkout: tsv (its name and directory),
+        /// jsonl (a JSON object per line, with every dimension),
+        /// or shell (variables to eval, like PANDO_0_DIR, and PANDO_COUNT).
+        #[structopt(long, default_value = "tsv", possible_values = PrintFormat::VARIANTS)]
+        format: PrintFormat,
+
+        /// End each name and directory with a NUL instead, for xargs -0 -n 2.
+        #[structopt(short = "0", long, conflicts_with = "format")]
+        null: bool,
+    },
+
+    /// Report on the results of the last run, without checking out or running anything.
+    #[structopt(name = "report")]
+    Report {
+        /// Only show the tests whose outcome differs between checkouts, as a matrix.
+        #[structopt(long, conflicts_with = "format")]
+        diff: bool,
+
+        /// How to print the results: human, json, markdown or tap.
+        #[structopt(long, default_value = "human", possible_values = OutputFormat::VARIANTS)]
+        format: OutputFormat,
+    },
+
+    /// List the kept runs, newest first, with how many checkouts succeeded.
+    #[structopt(name = "history")]
+    History,
+
+    /// Show what was run in a past run, and its results.
+    #[structopt(name = "show")]
+    Show {
+        /// The ID of the run, as listed by `cargo pando history`.
+        run_id: String,
+
+        /// How to print the results: human, json, markdown or tap.
+        #[structopt(long, default_value = "human", possible_values = OutputFormat::VARIANTS)]
+        format: OutputFormat,
+    },
+
+    /// Run the named pipeline from the pando config on each checkout.
+    ///
+    /// Each step runs in order, stopping at the first one to fail.
+    #[structopt(name = "pipeline")]
+    Pipeline {
+        /// Install the proper toolchain if it's not already present.
+        #[structopt(long)]
+        install: bool,
+
+        /// Max active tasks. Defaults to number of logical CPUs.
+        #[structopt(short, long)]
+        jobs: Option<usize>,
+
+        name: String,
+    },
 }
 
-// TODO: this abstraction sucks. Convert just like checkoutsource to 
-// an enum of cargo command, each, print
-// might be some way around needing scope for cargo that isn't yucky
 impl ActionOpt {
     pub fn job_count(&self) -> Option<usize> {
         match self {
-            ActionOpt::Each { jobs, .. } => jobs.clone(),
-            ActionOpt::CargoTest { jobs, .. } => jobs.clone(),
-            ActionOpt::CargoBuild { jobs, .. } => jobs.clone(),
-            ActionOpt::CargoAny { jobs, .. } => jobs.clone(),
-            ActionOpt::Print => Some(0),
+            ActionOpt::Each { jobs, .. } => *jobs,
+            ActionOpt::CargoTest { jobs, .. } => *jobs,
+            ActionOpt::CargoBuild { jobs, .. } => *jobs,
+            ActionOpt::CargoAny { jobs, .. } => *jobs,
+            ActionOpt::Pipeline { jobs, .. } => *jobs,
+            ActionOpt::Print { .. }
+            | ActionOpt::All { .. }
+            | ActionOpt::Report { .. }
+            | ActionOpt::History
+            | ActionOpt::Show { .. } => None,
         }
     }
-   
-    pub fn uses_progress_bars(&self) -> bool {
-        match self {
-            ActionOpt::Print => false,
-            _ => true,
+}
+
+/// How results are printed once everything has run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Human,
+    Json,
+    Markdown,
+    Tap,
+}
+
+impl OutputFormat {
+    pub const VARIANTS: &'static [&'static str] = &["human", "json", "markdown", "tap"];
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(OutputFormat::Human),
+            "json" => Ok(OutputFormat::Json),
+            "markdown" => Ok(OutputFormat::Markdown),
+            "tap" => Ok(OutputFormat::Tap),
+            _ => Err(format!("unknown format '{}'", s)),
         }
     }
+}
 
-    pub fn uses_workers(&self) -> bool {
-        match self {
-            ActionOpt::Print => false,
-            _ => true,
+/// Reports of the results, for --report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Markdown,
+}
+
+impl ReportFormat {
+    pub const VARIANTS: &'static [&'static str] = &["markdown"];
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "markdown" => Ok(ReportFormat::Markdown),
+            _ => Err(format!("unknown report format '{}'", s)),
+        }
+    }
+}
+
+/// How the print action prints each checkout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrintFormat {
+    Tsv,
+    /// Like tsv, but with every field ended by a NUL.
+    Null,
+    JsonLines,
+    Shell,
+}
+
+impl PrintFormat {
+    pub const VARIANTS: &'static [&'static str] = &["tsv", "jsonl", "shell"];
+}
+
+impl FromStr for PrintFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tsv" => Ok(PrintFormat::Tsv),
+            "jsonl" => Ok(PrintFormat::JsonLines),
+            "shell" => Ok(PrintFormat::Shell),
+            _ => Err(format!("unknown format '{}'", s)),
+        }
+    }
+}
+
+/// Which checkouts to stop once one fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailFast {
+    All,
+    /// Only those with the same toolchain.
+    PerDimension,
+}
+
+impl FailFast {
+    pub const VARIANTS: &'static [&'static str] = &["all", "per-dimension"];
+}
+
+impl FromStr for FailFast {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(FailFast::All),
+            "per-dimension" => Ok(FailFast::PerDimension),
+            _ => Err(format!("unknown fail-fast mode '{}'", s)),
         }
     }
 }
+
+/// Parse a size in bytes, with an optional K, M or G suffix (in powers of 1024).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, scale) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+        .ok_or_else(|| format!("invalid size '{}'", s))
+}
+
+/// Parse a duration like `500ms`, `30s`, `10m` or `1h`. Without a unit, it's seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let invalid = || format!("invalid duration '{}'", s);
+    let number = number.parse::<u64>().map_err(|_| invalid())?;
+    let secs = |scale: u64| {
+        number
+            .checked_mul(scale)
+            .map(Duration::from_secs)
+            .ok_or_else(invalid)
+    };
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => secs(60),
+        "h" => secs(60 * 60),
+        _ => Err(format!(
+            "invalid duration '{}': the unit must be ms, s, m or h",
+            s
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("4k"), Ok(4096));
+        assert_eq!(parse_size("2M"), Ok(2 << 20));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("1T").is_err());
+        assert_eq!(
+            parse_size("99999999999999G"),
+            Err("invalid size '99999999999999G'".to_string())
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10d").is_err());
+        assert!(parse_duration("-1s").is_err());
+    }
+
+    #[test]
+    fn durations_too_long() {
+        assert_eq!(
+            parse_duration("999999999999999999h"),
+            Err("invalid duration '999999999999999999h'".to_string())
+        );
+        assert!(parse_duration("18446744073709551615m").is_err());
+        assert_eq!(
+            parse_duration("18446744073709551615"),
+            Ok(Duration::from_secs(u64::max_value()))
+        );
+    }
+}
@@ -0,0 +1,278 @@
+//! Project configuration, read from `pando.toml` or Cargo.toml metadata.
+use super::action::{Step, StepProgram};
+use super::cargo::CargoMetadata;
+use super::cli::{parse_duration, parse_size};
+use super::locks::LockSpec;
+use super::CheckoutSource;
+use failure::{bail, format_err, Error, ResultExt};
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::time::Duration;
+
+pub const CONFIG_FILE: &str = "pando.toml";
+
+/// The whole pando configuration for a project.
+///
+/// The top-level values are defaults, which presets may override.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    toolchains: Option<Vec<String>>,
+    checkout: Option<CheckoutSource>,
+    jobs: Option<usize>,
+    build_jobs: Option<usize>,
+    timeout: Option<String>,
+    keep_runs: Option<usize>,
+    max_load: Option<f64>,
+    min_free_memory: Option<String>,
+    #[serde(default)]
+    shared_target_dir: bool,
+    #[serde(default)]
+    locks: Vec<String>,
+    rustc_wrapper: Option<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    #[serde(default)]
+    env_sets: BTreeMap<String, BTreeMap<String, String>>,
+    #[serde(default)]
+    pipelines: BTreeMap<String, Vec<StepConfig>>,
+    #[serde(default)]
+    presets: BTreeMap<String, Preset>,
+}
+
+/// A named set of overrides, selected with `--preset`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Preset {
+    toolchains: Option<Vec<String>>,
+    checkout: Option<CheckoutSource>,
+    jobs: Option<usize>,
+    build_jobs: Option<usize>,
+    timeout: Option<String>,
+    keep_runs: Option<usize>,
+    max_load: Option<f64>,
+    min_free_memory: Option<String>,
+    shared_target_dir: Option<bool>,
+    /// Replaces the top-level locks, like `toolchains`.
+    locks: Option<Vec<String>>,
+    rustc_wrapper: Option<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    #[serde(default)]
+    env_sets: Vec<String>,
+    pipeline: Option<String>,
+}
+
+/// One step of a pipeline. Exactly one of `cargo` or `each` must be given.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct StepConfig {
+    name: Option<String>,
+    cargo: Option<String>,
+    each: Option<String>,
+    #[serde(default)]
+    args: Vec<String>,
+    timeout: Option<String>,
+    #[serde(default)]
+    locks: Vec<String>,
+}
+
+/// The values from the config that apply to this run,
+/// after a preset and any env sets have been applied.
+#[derive(Debug, Default)]
+pub struct Settings {
+    pub toolchains: Option<Vec<String>>,
+    pub checkout: Option<CheckoutSource>,
+    pub jobs: Option<usize>,
+    pub build_jobs: Option<usize>,
+    pub timeout: Option<Duration>,
+    pub keep_runs: Option<usize>,
+    pub max_load: Option<f64>,
+    pub min_free_memory: Option<u64>,
+    pub shared_target_dir: bool,
+    /// Held by every step.
+    pub locks: Vec<LockSpec>,
+    pub rustc_wrapper: Option<String>,
+    pub env: BTreeMap<String, String>,
+    pub pipeline: Option<String>,
+}
+
+impl Config {
+    /// Resolve the settings for the given preset (if any),
+    /// layering env sets from the preset and then `extra_env_sets` on top.
+    pub fn settings(
+        &self,
+        preset: Option<&str>,
+        extra_env_sets: &[String],
+    ) -> Result<Settings, Error> {
+        let mut settings = Settings {
+            toolchains: self.toolchains.clone(),
+            checkout: self.checkout,
+            jobs: self.jobs,
+            build_jobs: self.build_jobs,
+            timeout: config_duration(self.timeout.as_ref().map(String::as_str))?,
+            keep_runs: self.keep_runs,
+            max_load: self.max_load,
+            min_free_memory: config_size(self.min_free_memory.as_ref().map(String::as_str))?,
+            shared_target_dir: self.shared_target_dir,
+            locks: config_locks(&self.locks)?,
+            rustc_wrapper: self.rustc_wrapper.clone(),
+            env: self.env.clone(),
+            pipeline: None,
+        };
+
+        if let Some(name) = preset {
+            let preset = self
+                .presets
+                .get(name)
+                .ok_or_else(|| format_err!("no preset named '{}' in the pando config", name))?;
+
+            if preset.toolchains.is_some() {
+                settings.toolchains = preset.toolchains.clone();
+            }
+            settings.checkout = preset.checkout.or(settings.checkout);
+            settings.jobs = preset.jobs.or(settings.jobs);
+            settings.build_jobs = preset.build_jobs.or(settings.build_jobs);
+            if preset.timeout.is_some() {
+                settings.timeout = config_duration(preset.timeout.as_ref().map(String::as_str))
+                    .with_context(|_| format!("preset '{}' is invalid", name))?;
+            }
+            settings.keep_runs = preset.keep_runs.or(settings.keep_runs);
+            settings.max_load = preset.max_load.or(settings.max_load);
+            if preset.min_free_memory.is_some() {
+                settings.min_free_memory =
+                    config_size(preset.min_free_memory.as_ref().map(String::as_str))
+                        .with_context(|_| format!("preset '{}' is invalid", name))?;
+            }
+            settings.shared_target_dir = preset
+                .shared_target_dir
+                .unwrap_or(settings.shared_target_dir);
+            if let Some(locks) = &preset.locks {
+                settings.locks = config_locks(locks)
+                    .with_context(|_| format!("preset '{}' is invalid", name))?;
+            }
+            if preset.rustc_wrapper.is_some() {
+                settings.rustc_wrapper = preset.rustc_wrapper.clone();
+            }
+            settings.pipeline = preset.pipeline.clone();
+            for set in &preset.env_sets {
+                settings.env.extend(self.env_set(set)?);
+            }
+            settings.env.extend(preset.env.clone());
+        }
+
+        for set in extra_env_sets {
+            settings.env.extend(self.env_set(set)?);
+        }
+
+        Ok(settings)
+    }
+
+    fn env_set(&self, name: &str) -> Result<BTreeMap<String, String>, Error> {
+        self.env_sets
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format_err!("no env set named '{}' in the pando config", name))
+    }
+
+    /// Get the steps of the named pipeline.
+    pub fn pipeline(&self, name: &str) -> Result<Vec<Step>, Error> {
+        let steps = self
+            .pipelines
+            .get(name)
+            .ok_or_else(|| format_err!("no pipeline named '{}' in the pando config", name))?;
+
+        if steps.is_empty() {
+            bail!("pipeline '{}' has no steps", name);
+        }
+
+        steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| {
+                step.to_step()
+                    .with_context(|_| format!("step {} of pipeline '{}' is invalid", i + 1, name))
+                    .map_err(Error::from)
+            })
+            .collect()
+    }
+}
+
+impl StepConfig {
+    fn to_step(&self) -> Result<Step, Error> {
+        let program = match (&self.cargo, &self.each) {
+            (Some(subcommand), None) => StepProgram::Cargo {
+                subcommand: subcommand.clone(),
+                args: self.args.clone(),
+            },
+            (None, Some(utility)) => StepProgram::Each {
+                utility: utility.clone(),
+                args: self.args.clone(),
+            },
+            _ => bail!("exactly one of 'cargo' or 'each' must be given"),
+        };
+
+        let mut step = Step::new(program);
+        if let Some(name) = &self.name {
+            step.name = name.clone();
+        }
+        step.timeout = config_duration(self.timeout.as_ref().map(String::as_str))?;
+        step.locks = config_locks(&self.locks)?;
+        Ok(step)
+    }
+}
+
+fn config_duration(duration: Option<&str>) -> Result<Option<Duration>, Error> {
+    duration
+        .map(|duration| parse_duration(duration).map_err(|e| format_err!("{}", e)))
+        .transpose()
+}
+
+fn config_size(size: Option<&str>) -> Result<Option<u64>, Error> {
+    size.map(|size| parse_size(size).map_err(|e| format_err!("{}", e)))
+        .transpose()
+}
+
+fn config_locks(locks: &[String]) -> Result<Vec<LockSpec>, Error> {
+    locks
+        .iter()
+        .map(|lock| lock.parse().map_err(|e| format_err!("{}", e)))
+        .collect()
+}
+
+/// Load the pando config for the current project.
+///
+/// `pando.toml` in the workspace root is used if present.
+/// Otherwise, `[package.metadata.pando]` of the current package is used,
+/// falling back to `[workspace.metadata.pando]`.
+/// If none of those exist, the config is empty.
+pub fn load(metadata: &CargoMetadata) -> Result<Config, Error> {
+    let file = Path::new(&metadata.workspace_root).join(CONFIG_FILE);
+    if file.exists() {
+        let text =
+            read_to_string(&file).with_context(|_| format!("could not read {}", file.display()))?;
+        let config =
+            toml::from_str(&text).with_context(|_| format!("{} was malformed", file.display()))?;
+        return Ok(config);
+    }
+
+    let package_config = metadata
+        .current_package()
+        .and_then(|package| package.metadata.get("pando"));
+
+    if let Some(value) = package_config {
+        let config = serde_yaml::from_value(value.clone())
+            .context("[package.metadata.pando] in Cargo.toml was malformed")?;
+        return Ok(config);
+    }
+
+    if let Some(value) = metadata.metadata.get("pando") {
+        let config = serde_yaml::from_value(value.clone())
+            .context("[workspace.metadata.pando] in Cargo.toml was malformed")?;
+        return Ok(config);
+    }
+
+    Ok(Config::default())
+}
@@ -41,8 +41,8 @@ impl Iterator for DirWalker {
     }
 }
 
-const CARGO_TOML: &'static str = "Cargo.toml";
-const CARGO_LOCK: &'static str = "Cargo.lock";
+const CARGO_TOML: &str = "Cargo.toml";
+const CARGO_LOCK: &str = "Cargo.lock";
 
 fn get_all_copy_targets(wdir: &Path) -> io::Result<Vec<(PathBuf, Metadata)>> {
     let cargo_toml = {
@@ -98,11 +98,11 @@ fn do_copy((src, meta): &(PathBuf, Metadata), target_dir: &Path) -> io::Result<(
 }
 
 /// Copy over all applicable files into each checkout.
-/// 
+///
 /// The finished_callback is called once for each checkout fully copied.
 pub fn copy_repo<'checkout, I>(
     checkouts: I,
-    mut finished_callback: impl FnMut(&'checkout Checkout)
+    mut finished_callback: impl FnMut(&'checkout Checkout),
 ) -> Result<bool, Error>
 where
     I: IntoIterator<Item = &'checkout Checkout>,
@@ -123,11 +123,7 @@ where
         for (i, file) in src.iter().enumerate() {
             checkout.progress.set_message(&copying_message(i));
             if let Err(e) = do_copy(file, &checkout.working_dir) {
-                checkout.progress.finish_with_message(&format!(
-                    "error copying {}: {}",
-                    file.0.display(),
-                    e
-                ));
+                checkout.checkout_failed(format!("error copying {}: {}", file.0.display(), e));
                 all_successful = false;
                 continue 'checkouts;
             }
@@ -0,0 +1,186 @@
+//! Compiler diagnostics from cargo's JSON messages, aggregated across checkouts.
+use super::Checkout;
+use console::style;
+use failure::{Error, ResultExt};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+/// A diagnostic as emitted by rustc, inside cargo's `compiler-message`.
+#[derive(Deserialize, Debug)]
+pub struct RustcDiagnostic {
+    message: String,
+    level: String,
+    code: Option<RustcDiagnosticCode>,
+    #[serde(default)]
+    spans: Vec<RustcSpan>,
+    pub rendered: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RustcDiagnosticCode {
+    code: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RustcSpan {
+    file_name: String,
+    line_start: u64,
+    column_start: u64,
+    is_primary: bool,
+}
+
+/// What makes two diagnostics from different toolchains "the same".
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Diagnostic {
+    pub level: String,
+    pub code: Option<String>,
+    pub message: String,
+    /// The primary span, as `file:line:column`.
+    pub location: Option<String>,
+}
+
+/// The diagnostics seen in a checkout, each with its rendered form.
+pub type Diagnostics = BTreeMap<Diagnostic, String>;
+
+impl RustcDiagnostic {
+    /// Convert to a comparable diagnostic.
+    ///
+    /// Returns `None` for summaries like "aborting due to previous error",
+    /// which point at no code and have no lint or error code.
+    pub fn to_diagnostic(&self) -> Option<Diagnostic> {
+        if self.spans.is_empty() && self.code.is_none() {
+            return None;
+        }
+
+        Some(Diagnostic {
+            level: self.level.clone(),
+            code: self.code.as_ref().map(|code| code.code.clone()),
+            message: self.message.clone(),
+            location: self.spans.iter().find(|span| span.is_primary).map(|span| {
+                format!(
+                    "{}:{}:{}",
+                    span.file_name, span.line_start, span.column_start
+                )
+            }),
+        })
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.level)?;
+        if let Some(code) = &self.code {
+            write!(f, "[{}]", code)?;
+        }
+        write!(f, ": {}", self.message)?;
+        if let Some(location) = &self.location {
+            write!(f, " at {}", location)?;
+        }
+        Ok(())
+    }
+}
+
+/// One diagnostic, and every checkout it appeared on.
+#[derive(Serialize, Debug)]
+pub struct AggregateDiagnostic<'a> {
+    #[serde(flatten)]
+    pub diagnostic: &'a Diagnostic,
+    pub rendered: &'a str,
+    /// The names of the checkouts, which are just the toolchains unless revisions were given.
+    pub toolchains: Vec<String>,
+    /// Whether it appeared on every checkout that reported diagnostics.
+    pub everywhere: bool,
+}
+
+/// The diagnostics of every checkout, deduplicated.
+#[derive(Serialize, Debug)]
+pub struct DiagnosticsReport<'a> {
+    /// The checkouts that reported diagnostics, by name.
+    pub toolchains: Vec<String>,
+    pub diagnostics: Vec<AggregateDiagnostic<'a>>,
+}
+
+impl<'a> DiagnosticsReport<'a> {
+    /// Aggregate the diagnostics collected for each checkout, given in the same order.
+    ///
+    /// Checkouts that collected none (e.g. had no cargo steps) are left out.
+    pub fn new(
+        checkouts: &'a [Checkout],
+        collected: &'a [Option<Diagnostics>],
+    ) -> DiagnosticsReport<'a> {
+        let mut toolchains = Vec::new();
+        let mut by_diagnostic = BTreeMap::<&Diagnostic, (&str, Vec<String>)>::new();
+
+        for (checkout, diagnostics) in checkouts.iter().zip(collected) {
+            let diagnostics = match diagnostics {
+                Some(diagnostics) => diagnostics,
+                None => continue,
+            };
+            let name = checkout.name();
+            toolchains.push(name.clone());
+
+            for (diagnostic, rendered) in diagnostics {
+                by_diagnostic
+                    .entry(diagnostic)
+                    .or_insert_with(|| (rendered, Vec::new()))
+                    .1
+                    .push(name.clone());
+            }
+        }
+
+        let diagnostics = by_diagnostic
+            .into_iter()
+            .map(|(diagnostic, (rendered, seen_on))| AggregateDiagnostic {
+                everywhere: seen_on.len() == toolchains.len(),
+                diagnostic,
+                rendered,
+                toolchains: seen_on,
+            })
+            .collect();
+
+        DiagnosticsReport {
+            toolchains,
+            diagnostics,
+        }
+    }
+
+    /// Print each diagnostic once, those not on every toolchain last and highlighted.
+    pub fn print(&self) {
+        if self.diagnostics.is_empty() {
+            println!("No diagnostics on {}.", self.toolchains.join(", "));
+            return;
+        }
+
+        let (everywhere, somewhere): (Vec<_>, Vec<_>) =
+            self.diagnostics.iter().partition(|agg| agg.everywhere);
+
+        if !everywhere.is_empty() {
+            println!("{}", style("On every toolchain:").bold());
+            for agg in everywhere {
+                println!("{}\n", agg.rendered);
+            }
+        }
+
+        if !somewhere.is_empty() {
+            println!("{}", style("Only on some toolchains:").yellow().bold());
+            for agg in somewhere {
+                println!("{}", agg.rendered);
+                println!(
+                    "{}\n",
+                    style(format!("    = only on: {}", agg.toolchains.join(", "))).yellow()
+                );
+            }
+        }
+    }
+
+    /// Write the report as JSON to the given path.
+    pub fn write_json(&self, path: &Path) -> Result<(), Error> {
+        let file =
+            File::create(path).with_context(|_| format!("could not create {}", path.display()))?;
+        serde_json::to_writer_pretty(file, self)
+            .with_context(|_| format!("could not write diagnostics to {}", path.display()))?;
+        Ok(())
+    }
+}
@@ -1,8 +1,9 @@
-//! Checkout the index/stage once for each toolchain.
+//! Checkout the index/stage or git revisions once for each toolchain.
 use super::Checkout;
-use failure::Error;
+use failure::{Error, ResultExt};
 use git2::build::CheckoutBuilder;
-use git2::Repository;
+use git2::{Oid, Repository};
+use serde_derive::{Deserialize, Serialize};
 
 /// Checkout the index/stage once for each toolchain.
 pub fn checkout_index<'checkout, I>(
@@ -23,9 +24,98 @@ where
         ckopt.recreate_missing(true);
 
         if let Err(e) = repo.checkout_index(None, Some(&mut ckopt)) {
+            checkout.checkout_failed(format!("checkout error: {}", e));
+            checkout_success = false;
+        } else {
             checkout
                 .progress
-                .finish_with_message(&format!("checkout error: {}", e));
+                .set_message("checked out, waiting on available worker");
+            checkout.progress.inc(1);
+            finished_callback(checkout);
+        }
+    }
+
+    Ok(checkout_success)
+}
+
+/// A git revision, resolved to the commit it names.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rev {
+    /// The revision as given, e.g. `HEAD~1`.
+    pub spec: String,
+    /// The full commit id.
+    pub id: String,
+}
+
+impl Rev {
+    /// An abbreviated commit id, for naming checkouts.
+    pub fn short_id(&self) -> &str {
+        &self.id[..8]
+    }
+}
+
+/// Resolve each revision to the commit it names.
+pub fn resolve_revs(specs: &[String]) -> Result<Vec<Rev>, Error> {
+    // don't require a git repo unless revisions were asked for
+    if specs.is_empty() {
+        return Ok(Vec::new());
+    }
+    let repo = Repository::open_from_env()?;
+
+    specs
+        .iter()
+        .map(|spec| {
+            let commit = repo
+                .revparse_single(spec)
+                .and_then(|object| object.peel_to_commit())
+                .with_context(|_| format!("could not resolve revision '{}'", spec))?;
+            Ok(Rev {
+                spec: spec.clone(),
+                id: commit.id().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The commit at HEAD, if in a git repo that has one.
+pub fn head_id() -> Option<String> {
+    let repo = Repository::open_from_env().ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    Some(commit.id().to_string())
+}
+
+/// Checkout the revision of each checkout.
+pub fn checkout_revs<'checkout, I>(
+    checkouts: I,
+    mut finished_callback: impl FnMut(&'checkout Checkout),
+) -> Result<bool, Error>
+where
+    I: IntoIterator<Item = &'checkout Checkout>,
+{
+    let repo = Repository::open_from_env()?;
+
+    let mut checkout_success = true;
+    for checkout in checkouts {
+        let rev = checkout
+            .rev
+            .as_ref()
+            .expect("checking out revisions for a checkout without one");
+
+        checkout.progress.set_message("checking out");
+        std::fs::create_dir_all(&checkout.working_dir)?; // TODO isolate
+        let mut ckopt = CheckoutBuilder::new();
+        ckopt.target_dir(&checkout.working_dir);
+        ckopt.recreate_missing(true);
+        ckopt.force();
+        // the index describes the user's working tree, not this one
+        ckopt.update_index(false);
+
+        let result = Oid::from_str(&rev.id)
+            .and_then(|id| repo.find_commit(id))
+            .and_then(|commit| repo.checkout_tree(commit.as_object(), Some(&mut ckopt)));
+
+        if let Err(e) = result {
+            checkout.checkout_failed(format!("checkout error: {}", e));
             checkout_success = false;
         } else {
             checkout
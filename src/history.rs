@@ -0,0 +1,280 @@
+//! Past runs, each kept in its own directory under `target/pando/runs`.
+use super::git::Rev;
+use super::report::{RunResults, RESULTS_FILE};
+use failure::{Error, ResultExt};
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{create_dir, create_dir_all, read_dir, remove_dir_all, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where runs are kept, within `target/pando`.
+pub const RUNS_DIR: &str = "runs";
+
+/// How many runs are kept by default.
+pub const DEFAULT_KEEP_RUNS: usize = 10;
+
+/// Where a run's metadata is kept, within its directory.
+const INFO_FILE: &str = "run.json";
+
+/// What was run, written when the run starts.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunInfo {
+    /// e.g. `20191018-142305`, from when the run started (in UTC).
+    pub id: String,
+    pub command_line: Vec<String>,
+    /// The commit at HEAD when the run started, if in a git repo.
+    pub head: Option<String>,
+    pub toolchains: Vec<String>,
+    pub revs: Vec<Rev>,
+}
+
+/// A run's directory, holding its metadata, results and logs.
+pub struct Run {
+    pub info: RunInfo,
+    pub dir: PathBuf,
+}
+
+impl Run {
+    /// Create the directory for a new run, and record what's being run.
+    pub fn start(
+        pando_dir: &Path,
+        command_line: Vec<String>,
+        head: Option<String>,
+        toolchains: Vec<String>,
+        revs: Vec<Rev>,
+    ) -> Result<Run, Error> {
+        let runs = pando_dir.join(RUNS_DIR);
+        create_dir_all(&runs).with_context(|_| format!("could not create {}", runs.display()))?;
+
+        // runs started in the same second get a suffix
+        let timestamp = timestamp(SystemTime::now());
+        let mut id = timestamp.clone();
+        let mut dir = runs.join(&id);
+        let mut n = 1;
+        loop {
+            match create_dir(&dir) {
+                Ok(()) => break,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    n += 1;
+                    id = format!("{}-{}", timestamp, n);
+                    dir = runs.join(&id);
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|_| format!("could not create {}", dir.display()))?
+                }
+            }
+        }
+
+        let info = RunInfo {
+            id,
+            command_line,
+            head,
+            toolchains,
+            revs,
+        };
+        let path = dir.join(INFO_FILE);
+        let file =
+            File::create(&path).with_context(|_| format!("could not create {}", path.display()))?;
+        serde_json::to_writer_pretty(file, &info)
+            .with_context(|_| format!("could not write {}", path.display()))?;
+
+        Ok(Run { info, dir })
+    }
+
+    /// Load a past run by its ID.
+    pub fn load(pando_dir: &Path, id: &str) -> Result<Run, Error> {
+        let dir = pando_dir.join(RUNS_DIR).join(id);
+        let path = dir.join(INFO_FILE);
+        let file = File::open(&path).with_context(|_| {
+            format!(
+                "could not open {}. See `cargo pando history` for past runs.",
+                path.display()
+            )
+        })?;
+        let info = serde_json::from_reader(file)
+            .with_context(|_| format!("{} was malformed", path.display()))?;
+        Ok(Run { info, dir })
+    }
+
+    /// Where the combined log of the named checkout goes.
+    pub fn log(&self, checkout_name: &str) -> PathBuf {
+        self.dir.join(format!("{}.log", checkout_name))
+    }
+
+    /// Where the log of just one stream (`stdout` or `stderr`) of the named checkout goes.
+    pub fn stream_log(&self, checkout_name: &str, stream: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}.log", checkout_name, stream))
+    }
+
+    /// Where the named checkout's steps can report their progress.
+    pub fn progress_file(&self, checkout_name: &str) -> PathBuf {
+        self.dir.join(format!("{}.progress", checkout_name))
+    }
+
+    pub fn results_file(&self) -> PathBuf {
+        self.dir.join(RESULTS_FILE)
+    }
+
+    /// The run's results, or `None` if it never finished.
+    pub fn results(&self) -> Result<Option<RunResults>, Error> {
+        let path = self.results_file();
+        if path.exists() {
+            Ok(Some(RunResults::load(&path)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Print what was run, e.g. before its results.
+    pub fn print_info(&self) {
+        println!("run:        {}", self.info.id);
+        println!("command:    {}", self.info.command_line.join(" "));
+        if let Some(head) = &self.info.head {
+            println!("head:       {}", head);
+        }
+        println!("toolchains: {}", self.info.toolchains.join(", "));
+        for rev in &self.info.revs {
+            println!("rev:        {} ({})", rev.spec, rev.id);
+        }
+    }
+}
+
+/// The IDs of every kept run, oldest first.
+pub fn run_ids(pando_dir: &Path) -> Result<Vec<String>, Error> {
+    let runs = pando_dir.join(RUNS_DIR);
+    if !runs.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut ids = Vec::new();
+    for entry in read_dir(&runs).with_context(|_| format!("could not read {}", runs.display()))? {
+        let entry = entry?;
+        if entry.path().join(INFO_FILE).exists() {
+            ids.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    ids.sort_by(|a, b| age(a).cmp(&age(b)));
+    Ok(ids)
+}
+
+/// What run IDs sort by to be in order of age: their timestamp, then their suffix,
+/// so `…-10` comes after `…-2`.
+fn age(id: &str) -> (&str, u32) {
+    // timestamps have one `-` of their own
+    match id.rfind('-') {
+        Some(i) if id[..i].contains('-') => (&id[..i], id[i + 1..].parse().unwrap_or(0)),
+        _ => (id, 1),
+    }
+}
+
+/// Print every kept run, newest first, with how many checkouts succeeded.
+pub fn print_history(pando_dir: &Path) -> Result<(), Error> {
+    let ids = run_ids(pando_dir)?;
+    if ids.is_empty() {
+        println!("No runs yet.");
+    }
+
+    for id in ids.iter().rev() {
+        let run = Run::load(pando_dir, id)?;
+        let status = match run.results()? {
+            Some(results) => format!(
+                "{}/{} succeeded",
+                results.checkouts.iter().filter(|c| c.success()).count(),
+                results.checkouts.len()
+            ),
+            None => "unfinished".to_string(),
+        };
+        println!("{}\t{}\t{}", id, status, run.info.command_line.join(" "));
+    }
+    Ok(())
+}
+
+/// Delete all but the newest `keep` runs.
+pub fn prune(pando_dir: &Path, keep: usize) -> Result<(), Error> {
+    let ids = run_ids(pando_dir)?;
+    let excess = ids.len().saturating_sub(keep.max(1));
+    for id in &ids[..excess] {
+        let dir = pando_dir.join(RUNS_DIR).join(id);
+        remove_dir_all(&dir).with_context(|_| format!("could not remove {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+/// Format a time as a run ID, e.g. `20191018-142305`.
+fn timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// The (year, month, day) of a day counted from 1970-01-01.
+///
+/// From Howard Hinnant's `civil_from_days`, simplified for days after the epoch.
+fn civil_from_days(days: u64) -> (u64, u32, u32) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(18_321), (2020, 2, 29));
+        assert_eq!(civil_from_days(18_322), (2020, 3, 1));
+        assert_eq!(civil_from_days(47_540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47_541), (2100, 3, 1));
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp(UNIX_EPOCH), "19700101-000000");
+        let time = UNIX_EPOCH + Duration::from_secs(18_321 * 86400 + 12 * 3600 + 34 * 60 + 56);
+        assert_eq!(timestamp(time), "20200229-123456");
+    }
+
+    #[test]
+    fn ages() {
+        let mut ids = vec![
+            "20191018-142305-10",
+            "20191018-142305-2",
+            "20191018-142306",
+            "20191018-142305",
+        ];
+        ids.sort_by(|a, b| age(a).cmp(&age(b)));
+        assert_eq!(
+            ids,
+            [
+                "20191018-142305",
+                "20191018-142305-2",
+                "20191018-142305-10",
+                "20191018-142306"
+            ]
+        );
+    }
+}
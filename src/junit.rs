@@ -0,0 +1,146 @@
+//! JUnit XML output, for CI dashboards.
+use super::libtest::TestOutcome;
+use super::report::{CheckoutResults, RunResults};
+use failure::{Error, ResultExt};
+use std::fs::{read, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Write the results as JUnit XML, with one testsuite per checkout.
+///
+/// Each parsed test becomes a testcase. Checkouts without parsed tests get
+/// a single testcase for the whole checkout, with its log as `system-out`,
+/// as do checkouts that failed without any of their tests failing.
+/// Skipped and cancelled checkouts are reported as skipped, not failed.
+pub fn write_junit(results: &RunResults, path: &Path) -> Result<(), Error> {
+    let file =
+        File::create(path).with_context(|_| format!("could not create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+
+    write_suites(results, &mut out)
+        .with_context(|_| format!("could not write JUnit XML to {}", path.display()))?;
+    Ok(())
+}
+
+fn write_suites(results: &RunResults, out: &mut impl Write) -> Result<(), Error> {
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, "<testsuites>")?;
+    for checkout in &results.checkouts {
+        if checkout.tests.is_empty() {
+            write_whole_checkout(checkout, out)?;
+        } else {
+            write_tests(checkout, out)?;
+        }
+    }
+    writeln!(out, "</testsuites>")?;
+    out.flush()?;
+    Ok(())
+}
+
+fn write_tests(checkout: &CheckoutResults, out: &mut impl Write) -> Result<(), Error> {
+    let name = escape(&checkout.name());
+    let count = |outcome| {
+        checkout
+            .tests
+            .iter()
+            .filter(|test| test.outcome == outcome)
+            .count()
+    };
+
+    // a checkout can fail (or be cancelled) after its tests pass, e.g. in a later step
+    let checkout_case = !checkout.success() && count(TestOutcome::Failed) == 0;
+    let (extra_failure, extra_skipped) = match (checkout_case, checkout.skipped()) {
+        (false, _) => (0, 0),
+        (true, false) => (1, 0),
+        (true, true) => (0, 1),
+    };
+
+    writeln!(
+        out,
+        r#"  <testsuite name="{}" tests="{}" failures="{}" skipped="{}" errors="0">"#,
+        name,
+        checkout.tests.len() + extra_failure + extra_skipped,
+        count(TestOutcome::Failed) + extra_failure,
+        count(TestOutcome::Ignored) + extra_skipped,
+    )?;
+    for test in &checkout.tests {
+        let start = format!(
+            r#"    <testcase name="{}" classname="{}""#,
+            escape(&test.name),
+            name
+        );
+        match test.outcome {
+            TestOutcome::Passed => writeln!(out, "{}/>", start)?,
+            TestOutcome::Failed => writeln!(
+                out,
+                r#"{}><failure message="test failed"/></testcase>"#,
+                start
+            )?,
+            TestOutcome::Ignored => writeln!(out, "{}><skipped/></testcase>", start)?,
+        }
+    }
+    if checkout_case {
+        write_checkout_case(checkout, out)?;
+    }
+    writeln!(out, "  </testsuite>")?;
+    Ok(())
+}
+
+fn write_whole_checkout(checkout: &CheckoutResults, out: &mut impl Write) -> Result<(), Error> {
+    let failed = !checkout.success() && !checkout.skipped();
+    writeln!(
+        out,
+        r#"  <testsuite name="{}" tests="1" failures="{}" skipped="{}" errors="0">"#,
+        escape(&checkout.name()),
+        if failed { 1 } else { 0 },
+        if checkout.skipped() { 1 } else { 0 },
+    )?;
+    write_checkout_case(checkout, out)?;
+    writeln!(out, "  </testsuite>")?;
+    Ok(())
+}
+
+/// A testcase for the whole checkout, with its log.
+fn write_checkout_case(checkout: &CheckoutResults, out: &mut impl Write) -> Result<(), Error> {
+    let name = escape(&checkout.name());
+    let log = read(&checkout.output)
+        .map(|log| String::from_utf8_lossy(&log).into_owned())
+        .unwrap_or_default();
+
+    writeln!(
+        out,
+        r#"    <testcase name="{}" classname="{}">"#,
+        name, name
+    )?;
+    if checkout.skipped() {
+        writeln!(
+            out,
+            r#"      <skipped message="{}"/>"#,
+            escape(&checkout.describe())
+        )?;
+    } else if !checkout.success() {
+        writeln!(out, r#"      <failure message="checkout failed"/>"#)?;
+    }
+    writeln!(out, "      <system-out>{}</system-out>", escape(&log))?;
+    writeln!(out, "    </testcase>")?;
+    Ok(())
+}
+
+/// Escape text for use in XML, dropping characters XML can't contain
+/// (like the escape codes of colored output).
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < ' ' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
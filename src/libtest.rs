@@ -0,0 +1,240 @@
+//! Per-test results, parsed from the output of libtest.
+use serde_derive::{Deserialize, Serialize};
+
+/// How a single test went.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TestOutcome {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+/// The result of a single test.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: TestOutcome,
+}
+
+/// An event from libtest's `--format json`.
+#[derive(Deserialize, Debug)]
+struct JsonEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    event: String,
+    name: Option<String>,
+}
+
+/// Parse a line of test output, if it's the result of a test.
+///
+/// Understands both the human format (`test a::b ... ok`)
+/// and the unstable JSON format (`-Z unstable-options --format json`).
+pub fn parse_line(line: &str) -> Option<TestResult> {
+    if line.starts_with('{') {
+        parse_json(line)
+    } else {
+        parse_human(line)
+    }
+}
+
+fn parse_human(line: &str) -> Option<TestResult> {
+    if !line.starts_with("test ") {
+        return None;
+    }
+    let rest = &line[5..];
+    // doc test names contain spaces, so split on the last separator
+    let separator = rest.rfind(" ... ")?;
+    let (name, result) = (&rest[..separator], &rest[separator + 5..]);
+
+    let outcome = if result.starts_with("ok") {
+        TestOutcome::Passed
+    } else if result.starts_with("FAILED") {
+        TestOutcome::Failed
+    } else if result.starts_with("ignored") {
+        TestOutcome::Ignored
+    } else {
+        return None;
+    };
+
+    Some(TestResult {
+        name: name.to_string(),
+        outcome,
+    })
+}
+
+fn parse_json(line: &str) -> Option<TestResult> {
+    let event: JsonEvent = serde_json::from_str(line).ok()?;
+    if event.kind != "test" {
+        return None;
+    }
+
+    let outcome = match event.event.as_str() {
+        "ok" => TestOutcome::Passed,
+        "failed" | "timeout" => TestOutcome::Failed,
+        "ignored" => TestOutcome::Ignored,
+        _ => return None,
+    };
+
+    Some(TestResult {
+        name: event.name?,
+        outcome,
+    })
+}
+
+/// Whether a line of `cargo test`'s output says a test binary exited other than
+/// how libtest does when tests fail, e.g. because it crashed after some tests failed.
+pub fn is_abnormal_exit(line: &str) -> bool {
+    let line = line.trim_end();
+    // older versions of cargo say "exit code"
+    line.contains("process didn't exit successfully")
+        && !line.ends_with("(exit status: 101)")
+        && !line.ends_with("(exit code: 101)")
+}
+
+/// The names of the tests that failed, for describing them.
+pub struct Failures<'a> {
+    names: Vec<&'a str>,
+}
+
+impl<'a> Failures<'a> {
+    /// Returns `None` if no tests failed.
+    pub fn new(results: &'a [TestResult]) -> Option<Failures<'a>> {
+        let names = results
+            .iter()
+            .filter(|result| result.outcome == TestOutcome::Failed)
+            .map(|result| result.name.as_str())
+            .collect::<Vec<_>>();
+
+        if names.is_empty() {
+            None
+        } else {
+            Some(Failures { names })
+        }
+    }
+
+    /// e.g. `3 tests failed`
+    pub fn count(&self) -> String {
+        match self.names.len() {
+            1 => "1 test failed".to_string(),
+            n => format!("{} tests failed", n),
+        }
+    }
+
+    /// e.g. `a::b, c::d, e`, abbreviated if there are many.
+    pub fn names(&self) -> String {
+        const SHOWN: usize = 5;
+
+        let mut names = self.names[..self.names.len().min(SHOWN)].join(", ");
+        if self.names.len() > SHOWN {
+            names.push_str(&format!(" and {} more", self.names.len() - SHOWN));
+        }
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(line: &str) -> Option<(String, TestOutcome)> {
+        parse_line(line).map(|result| (result.name, result.outcome))
+    }
+
+    #[test]
+    fn parse_human() {
+        assert_eq!(
+            parsed("test a::b ... ok"),
+            Some(("a::b".to_string(), TestOutcome::Passed))
+        );
+        assert_eq!(
+            parsed("test a::b ... FAILED"),
+            Some(("a::b".to_string(), TestOutcome::Failed))
+        );
+        assert_eq!(
+            parsed("test a::b ... ignored, needs a database"),
+            Some(("a::b".to_string(), TestOutcome::Ignored))
+        );
+        assert_eq!(parsed("test result: FAILED. 1 passed; 1 failed"), None);
+        assert_eq!(
+            parsed("test a::b has been running for over 60 seconds"),
+            None
+        );
+        assert_eq!(parsed("running 2 tests"), None);
+    }
+
+    #[test]
+    fn parse_human_doc_tests() {
+        assert_eq!(
+            parsed("test src/lib.rs - Foo::bar (line 12) ... ok"),
+            Some((
+                "src/lib.rs - Foo::bar (line 12)".to_string(),
+                TestOutcome::Passed
+            ))
+        );
+        assert_eq!(
+            parsed("test src/lib.rs - a ... b (line 3) ... FAILED"),
+            Some((
+                "src/lib.rs - a ... b (line 3)".to_string(),
+                TestOutcome::Failed
+            ))
+        );
+    }
+
+    #[test]
+    fn abnormal_exit() {
+        let exited = |status| {
+            format!(
+                "  process didn't exit successfully: `/p/target/debug/deps/a-1f2e` ({})",
+                status
+            )
+        };
+        assert!(!is_abnormal_exit(&exited("exit status: 101")));
+        assert!(!is_abnormal_exit(&exited("exit code: 101")));
+        assert!(is_abnormal_exit(&exited("exit status: 1")));
+        assert!(is_abnormal_exit(&exited(
+            "signal: 11, SIGSEGV: invalid memory reference"
+        )));
+        assert!(!is_abnormal_exit(
+            "error: test failed, to rerun pass `--bin a`"
+        ));
+    }
+
+    #[test]
+    fn parse_json() {
+        assert_eq!(
+            parsed(r#"{ "type": "test", "event": "ok", "name": "a::b" }"#),
+            Some(("a::b".to_string(), TestOutcome::Passed))
+        );
+        assert_eq!(
+            parsed(r#"{ "type": "test", "event": "failed", "name": "a::b", "stdout": "" }"#),
+            Some(("a::b".to_string(), TestOutcome::Failed))
+        );
+        assert_eq!(
+            parsed(r#"{ "type": "test", "event": "timeout", "name": "a::b" }"#),
+            Some(("a::b".to_string(), TestOutcome::Failed))
+        );
+        assert_eq!(
+            parsed(r#"{ "type": "test", "event": "ignored", "name": "a::b" }"#),
+            Some(("a::b".to_string(), TestOutcome::Ignored))
+        );
+        assert_eq!(
+            parsed(r#"{ "type": "test", "event": "started", "name": "a::b" }"#),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_json_without_name() {
+        assert_eq!(
+            parsed(r#"{ "type": "suite", "event": "started", "test_count": 2 }"#),
+            None
+        );
+        assert_eq!(
+            parsed(r#"{ "type": "suite", "event": "failed", "passed": 1 }"#),
+            None
+        );
+        assert_eq!(parsed(r#"{ "type": "test", "event": "ok" }"#), None);
+        assert_eq!(parsed("{ not json"), None);
+    }
+}
@@ -0,0 +1,151 @@
+//! Named locks, for resources that only so many checkouts can use at once, e.g. a database.
+use failure::{bail, Error};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
+
+/// A lock as given on the command line or in the config: `db`, or `db=2`
+/// to let two steps hold it at once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockSpec {
+    pub name: String,
+    pub permits: usize,
+}
+
+impl FromStr for LockSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, permits) = match s.find('=') {
+            Some(i) => {
+                let permits = s[i + 1..]
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|permits| *permits > 0)
+                    .ok_or_else(|| format!("invalid lock '{}': the count must be at least 1", s))?;
+                (&s[..i], permits)
+            }
+            None => (s, 1),
+        };
+        if name.is_empty() {
+            return Err(format!("invalid lock '{}': it needs a name", s));
+        }
+        Ok(LockSpec {
+            name: name.to_string(),
+            permits,
+        })
+    }
+}
+
+/// Every lock used by the job's steps, shared by all checkouts.
+#[derive(Default)]
+pub struct Locks {
+    semaphores: BTreeMap<String, Semaphore>,
+}
+
+impl Locks {
+    /// Create the given locks. A lock may be given more than once, but always with the same count.
+    pub fn new<'a>(specs: impl IntoIterator<Item = &'a LockSpec>) -> Result<Locks, Error> {
+        let mut semaphores = BTreeMap::<String, Semaphore>::new();
+        for spec in specs {
+            match semaphores.get(&spec.name) {
+                Some(semaphore) if semaphore.permits != spec.permits => bail!(
+                    "lock '{}' is given with both {} and {} permits",
+                    spec.name,
+                    semaphore.permits,
+                    spec.permits
+                ),
+                Some(_) => {}
+                None => {
+                    semaphores.insert(spec.name.clone(), Semaphore::new(spec.permits));
+                }
+            }
+        }
+        Ok(Locks { semaphores })
+    }
+
+    /// Wait for every one of the named locks, holding them until the returned guards are dropped.
+    ///
+    /// They're always taken in the same order, so steps waiting on several can't deadlock.
+    pub fn acquire(&self, specs: &[LockSpec]) -> Vec<Permit<'_>> {
+        self.semaphores
+            .iter()
+            .filter(|(name, _)| specs.iter().any(|spec| &spec.name == *name))
+            .map(|(_, semaphore)| semaphore.acquire())
+            .collect()
+    }
+}
+
+struct Semaphore {
+    permits: usize,
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits,
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> Permit<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        Permit(self)
+    }
+}
+
+/// A held lock, released when dropped.
+pub struct Permit<'a>(&'a Semaphore);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().unwrap() += 1;
+        self.0.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn specs() {
+        assert_eq!(
+            "db".parse(),
+            Ok(LockSpec {
+                name: "db".to_string(),
+                permits: 1
+            })
+        );
+        assert_eq!(
+            "db=2".parse(),
+            Ok(LockSpec {
+                name: "db".to_string(),
+                permits: 2
+            })
+        );
+    }
+
+    #[test]
+    fn bad_specs() {
+        assert_eq!(
+            "db=0".parse::<LockSpec>(),
+            Err("invalid lock 'db=0': the count must be at least 1".to_string())
+        );
+        assert_eq!(
+            "db=x".parse::<LockSpec>(),
+            Err("invalid lock 'db=x': the count must be at least 1".to_string())
+        );
+        assert_eq!(
+            "=2".parse::<LockSpec>(),
+            Err("invalid lock '=2': it needs a name".to_string())
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Capturing the output of steps, and logging it.
+use super::report::secs;
+use failure::{Error, ResultExt};
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::fs::{rename, File};
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Lines longer than this are split up, so memory use stays bounded.
+const MAX_LINE: u64 = 64 * 1024;
+
+/// How a checkout's output is logged.
+#[derive(Debug, Clone, Default)]
+pub struct LogOptions {
+    /// Prefix each line with the time since the checkout started running.
+    pub elapsed: bool,
+    /// Prefix each line of the combined log with the stream it came from.
+    pub streams: bool,
+    /// Rotate a log once it's at least this many bytes.
+    pub max_size: Option<u64>,
+    /// How many rotated logs to keep. With none, a log is cut off at `max_size` instead.
+    pub rotations: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// A line of output from a step, and when it was read.
+pub struct OutputLine {
+    pub stream: Stream,
+    pub elapsed: Duration,
+    /// Exactly as output, including the newline if there was one.
+    pub bytes: Vec<u8>,
+}
+
+/// Read lines as raw bytes, passing each to `f`.
+///
+/// Lines longer than `MAX_LINE` are passed along in pieces.
+pub fn read_lines(mut reader: impl BufRead, mut f: impl FnMut(Vec<u8>)) -> io::Result<()> {
+    loop {
+        let mut line = Vec::new();
+        (&mut reader).take(MAX_LINE).read_until(b'\n', &mut line)?;
+        if line.is_empty() {
+            return Ok(());
+        }
+        f(line);
+    }
+}
+
+/// A line without its line ending, with any invalid UTF-8 replaced, for parsing.
+pub fn text(line: &[u8]) -> Cow<'_, str> {
+    let mut line = line;
+    if line.ends_with(b"\n") {
+        line = &line[..line.len() - 1];
+    }
+    if line.ends_with(b"\r") {
+        line = &line[..line.len() - 1];
+    }
+    String::from_utf8_lossy(line)
+}
+
+/// Where the log of an attempt at a checkout's job goes, given where the first attempt's goes.
+///
+/// e.g. `stable.attempt2.log` for `stable.log`.
+pub fn attempt_log(path: &Path, attempt: usize) -> PathBuf {
+    if attempt <= 1 {
+        path.to_path_buf()
+    } else {
+        path.with_extension(format!("attempt{}.log", attempt))
+    }
+}
+
+/// Where a checkout's output is logged: both streams together, and each on its own.
+pub struct Logs {
+    combined: LogFile,
+    stdout: LogFile,
+    stderr: LogFile,
+}
+
+impl Logs {
+    pub fn create(combined: &Path, stdout: &Path, stderr: &Path) -> Result<Logs, Error> {
+        Ok(Logs {
+            combined: LogFile::create(combined)?,
+            stdout: LogFile::create(stdout)?,
+            stderr: LogFile::create(stderr)?,
+        })
+    }
+
+    pub fn write(&mut self, line: &OutputLine, options: &LogOptions) -> io::Result<()> {
+        let elapsed = if options.elapsed {
+            format!("[{:>9.3}s] ", secs(line.elapsed))
+        } else {
+            String::new()
+        };
+        let (file, marker) = match line.stream {
+            Stream::Stdout => (&mut self.stdout, "out| "),
+            Stream::Stderr => (&mut self.stderr, "err| "),
+        };
+        let marker = if options.streams { marker } else { "" };
+
+        file.write(&elapsed, &line.bytes, options)?;
+        self.combined
+            .write(&format!("{}{}", elapsed, marker), &line.bytes, options)
+    }
+}
+
+/// A single log, which keeps track of its size to rotate it.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// Whether the next write starts a new line, and so gets the prefix.
+    line_start: bool,
+    /// Whether the log hit its max size with no rotations, so nothing more is written.
+    cut_off: bool,
+}
+
+impl LogFile {
+    fn create(path: &Path) -> Result<LogFile, Error> {
+        let file = File::create(path)
+            .with_context(|_| format!("error creating output file {}", path.display()))?;
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            file,
+            size: 0,
+            line_start: true,
+            cut_off: false,
+        })
+    }
+
+    fn write(&mut self, prefix: &str, bytes: &[u8], options: &LogOptions) -> io::Result<()> {
+        if self.cut_off {
+            return Ok(());
+        }
+        if let Some(max_size) = options.max_size {
+            if self.size >= max_size {
+                if options.rotations == 0 {
+                    let note = format!("\n[pando: log cut off at {} bytes]\n", max_size);
+                    self.file.write_all(note.as_bytes())?;
+                    self.cut_off = true;
+                    return Ok(());
+                }
+                self.rotate(options.rotations)?;
+            }
+        }
+
+        if self.line_start {
+            self.file.write_all(prefix.as_bytes())?;
+            self.size += prefix.len() as u64;
+        }
+        self.file.write_all(bytes)?;
+        self.size += bytes.len() as u64;
+        self.line_start = bytes.ends_with(b"\n");
+        Ok(())
+    }
+
+    /// Move `x.log` to `x.log.1`, `x.log.1` to `x.log.2`, etc., and start a new `x.log`.
+    fn rotate(&mut self, rotations: usize) -> io::Result<()> {
+        for n in (1..rotations).rev() {
+            let from = rotated(&self.path, n);
+            if from.exists() {
+                rename(&from, rotated(&self.path, n + 1))?;
+            }
+        }
+        rename(&self.path, rotated(&self.path, 1))?;
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(format!(".{}", n));
+    PathBuf::from(path)
+}
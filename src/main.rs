@@ -1,55 +1,289 @@
 mod action;
+mod all;
+mod cache;
+mod cancel;
 mod cargo;
 mod cli;
+mod config;
 mod copy;
+mod diagnostics;
 mod git;
+mod history;
+mod junit;
+mod libtest;
+mod locks;
+mod logs;
+mod markdown;
+mod placeholders;
+mod print;
+mod process;
+mod progress;
+mod report;
+mod resources;
+mod tap;
 mod toolchains;
 
-use action::run_cmd;
+use action::{run_cmd, Job, Step, StepProgram};
+use cache::{CompilerCache, SharedTargets};
 use cargo::CargoMetadata;
-use cli::{ActionOpt, Opts};
+use cli::{ActionOpt, FailFast, Opts, OutputFormat, PrintFormat, ReportFormat};
 use crossbeam::channel::bounded;
 use crossbeam::scope;
 use crossbeam::thread::ScopedJoinHandle;
+use diagnostics::{Diagnostics, DiagnosticsReport};
 use failure::{bail, format_err, Error, ResultExt};
+use git::Rev;
+use history::Run;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use jobserver::Client;
+use libtest::{Failures, TestResult};
+use locks::Locks;
+use logs::LogOptions;
+use report::{Attempt, Outcome, RunResults, Status, RESULTS_FILE};
+use resources::{Limits, Throttle};
+use serde_derive::Deserialize;
 use std::mem::drop;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 use structopt::StructOpt;
 
 // the parsed-and-proper program obtained from the structopt Opts.
 struct Program {
     toolchains: Vec<String>,
+    revs: Vec<Rev>,
     checkout_source: CheckoutSource,
-    action: ActionOpt,
+    action: Action,
+    jobs: Option<usize>,
+    print_diagnostics: bool,
+    diagnostics_json: Option<PathBuf>,
+    junit: Option<PathBuf>,
+    format: OutputFormat,
+    summary_json: Option<PathBuf>,
+    tap_tests: bool,
+    tap_log_lines: usize,
+    show_progress: bool,
+    fail_fast: Option<FailFast>,
+    /// When to hold off on starting more checkouts.
+    limits: Limits,
+    /// How pando was invoked, for the run's history.
+    command_line: Vec<String>,
+    keep_runs: usize,
     cargo_metadata: CargoMetadata,
 }
 
-fn opts_to_program(opts: Opts) -> Result<Program, Error> {
-    Ok(Program {
-        toolchains: if opts.all {
-            toolchains::get_installed_toolchains()?
-        } else if !opts.toolchain.is_empty() {
-            opts.toolchain
-        } else {
-            toolchains::get_toolchains_from_travis()?
+/// What to do with the checkouts once they're ready.
+enum Action {
+    Print(PrintFormat),
+    /// Run one command with every checkout.
+    All {
+        utility: String,
+        args: Vec<String>,
+    },
+    Run(Box<Job>),
+}
+
+fn opts_to_program(opts: Opts, command_line: Vec<String>) -> Result<Program, Error> {
+    let cargo_metadata = cargo::get_cargo_metadata();
+    let config = config::load(&cargo_metadata)?;
+    let settings = config.settings(opts.preset.as_ref().map(String::as_str), &opts.env_set)?;
+
+    let jobs = opts
+        .action
+        .as_ref()
+        .and_then(ActionOpt::job_count)
+        .or(settings.jobs);
+    let build_jobs = opts
+        .build_jobs
+        .or(settings.build_jobs)
+        .unwrap_or_else(num_cpus::get)
+        .max(1);
+
+    // the actions that don't run steps
+    let without_steps = match &opts.action {
+        Some(ActionOpt::Print { format, null }) => {
+            Action::Print(if *null { PrintFormat::Null } else { *format })
+        }
+        Some(ActionOpt::All { utility, args }) => Action::All {
+            utility: utility.clone(),
+            args: args.clone(),
         },
+        _ => Action::Print(PrintFormat::Tsv),
+    };
+
+    let (steps, install) = match opts.action {
+        Some(ActionOpt::Print { .. }) | Some(ActionOpt::All { .. }) => (None, false),
+        Some(ActionOpt::Report { .. })
+        | Some(ActionOpt::History)
+        | Some(ActionOpt::Show { .. }) => unreachable!("reports don't need a program"),
+        Some(ActionOpt::CargoTest {
+            install, test_args, ..
+        }) => (Some(vec![cargo_step("test", test_args)]), install),
+        Some(ActionOpt::CargoBuild {
+            install,
+            build_args,
+            ..
+        }) => (Some(vec![cargo_step("build", build_args)]), install),
+        Some(ActionOpt::CargoAny {
+            install,
+            subcommand,
+            args,
+            ..
+        }) => (Some(vec![cargo_step(&subcommand, args)]), install),
+        Some(ActionOpt::Each {
+            install,
+            utility,
+            args,
+            ..
+        }) => (
+            Some(vec![Step::new(StepProgram::Each { utility, args })]),
+            install,
+        ),
+        Some(ActionOpt::Pipeline { install, name, .. }) => (Some(config.pipeline(&name)?), install),
+        None => match &settings.pipeline {
+            Some(name) => (Some(config.pipeline(name)?), false),
+            None => bail!(
+                "no subcommand given, and no pipeline given by a preset. See `cargo pando help`."
+            ),
+        },
+    };
+
+    // locks for the whole job are held by every step
+    let job_locks = settings
+        .locks
+        .iter()
+        .chain(&opts.lock)
+        .cloned()
+        .collect::<Vec<_>>();
+    let steps = steps.map(|mut steps| {
+        for step in &mut steps {
+            step.locks.extend(job_locks.iter().cloned());
+        }
+        steps
+    });
+
+    let toolchains = if opts.all {
+        toolchains::get_installed_toolchains()?
+    } else if !opts.toolchain.is_empty() {
+        opts.toolchain
+    } else if let Some(toolchains) = settings.toolchains {
+        toolchains
+    } else {
+        toolchains::get_toolchains_from_travis()?
+    };
+    let revs = git::resolve_revs(&opts.rev)?;
+
+    if steps.is_some() {
+        let checkouts = toolchains.len() * revs.len().max(1);
+        check_ports(opts.port_base, opts.ports_per_checkout, checkouts)?;
+    }
+
+    let action = match steps {
+        Some(steps) => Action::Run(Box::new(Job {
+            locks: Locks::new(steps.iter().flat_map(|step| &step.locks))?,
+            port_base: opts.port_base,
+            ports_per_checkout: opts.ports_per_checkout,
+            steps,
+            install,
+            env: settings.env,
+            original_dir: std::env::current_dir().context("could not get the current directory")?,
+            run_id: None,
+            crate_count: cargo_metadata.crate_count(),
+            log: LogOptions {
+                elapsed: opts.log_elapsed,
+                streams: opts.log_streams,
+                max_size: opts.max_log_size,
+                rotations: opts.log_rotations,
+            },
+            timeout: opts.timeout.or(settings.timeout),
+            timeout_quit: opts.timeout_quit,
+            jobserver: Client::new(build_jobs).context("could not create a jobserver")?,
+            shared_targets: if opts.shared_target_dir || settings.shared_target_dir {
+                Some(SharedTargets::new(&pando_dir(&cargo_metadata)))
+            } else {
+                None
+            },
+            compiler_cache: opts
+                .rustc_wrapper
+                .or(settings.rustc_wrapper)
+                .map(|wrapper| CompilerCache { wrapper }),
+            retries: opts.retries,
+            retry_failed_tests: opts.retry_failed_tests,
+        })),
+        None => without_steps,
+    };
+
+    Ok(Program {
+        toolchains,
+        revs,
         checkout_source: if opts.index {
             CheckoutSource::Index
         } else if opts.no_copy {
             CheckoutSource::None
-        } else {
+        } else if !opts.rev.is_empty() {
+            CheckoutSource::Revs
+        } else if opts.copy {
             CheckoutSource::Copy
+        } else {
+            settings.checkout.unwrap_or(CheckoutSource::Copy)
+        },
+        action,
+        jobs,
+        print_diagnostics: opts.diagnostics,
+        diagnostics_json: opts.diagnostics_json,
+        junit: opts.junit,
+        format: output_format(opts.format, opts.report)?,
+        summary_json: opts.summary_json,
+        tap_tests: opts.tap_tests,
+        tap_log_lines: opts.tap_log_lines,
+        show_progress: !opts.no_progress,
+        fail_fast: opts.fail_fast.map(|mode| mode.unwrap_or(FailFast::All)),
+        limits: Limits {
+            max_load: opts.max_load.or(settings.max_load),
+            min_memory: opts.min_free_memory.or(settings.min_free_memory),
         },
-        action: opts.action,
-        cargo_metadata: cargo::get_cargo_metadata(),
+        command_line,
+        keep_runs: opts
+            .keep_runs
+            .or(settings.keep_runs)
+            .unwrap_or(history::DEFAULT_KEEP_RUNS),
+        cargo_metadata,
     })
 }
 
-enum CheckoutSource {
+/// Make sure every checkout's range of ports fits below 65536.
+fn check_ports(port_base: usize, ports_per_checkout: usize, checkouts: usize) -> Result<(), Error> {
+    let end = ports_per_checkout
+        .checked_mul(checkouts)
+        .and_then(|ports| ports.checked_add(port_base));
+    match end {
+        Some(end) if end <= 65536 => Ok(()),
+        _ => bail!(
+            "{} checkouts with {} ports each, starting at {}, go past port 65535. \
+             Try a lower --port-base or --ports-per-checkout.",
+            checkouts,
+            ports_per_checkout,
+            port_base
+        ),
+    }
+}
+
+fn cargo_step(subcommand: &str, args: Vec<String>) -> Step {
+    Step::new(StepProgram::Cargo {
+        subcommand: subcommand.to_string(),
+        args,
+    })
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckoutSource {
     Copy,
     Index,
     None,
+    /// Set by giving revisions, rather than in the config.
+    #[serde(skip_deserializing)]
+    Revs,
 }
 
 impl CheckoutSource {
@@ -61,6 +295,7 @@ impl CheckoutSource {
         match self {
             CheckoutSource::Index => git::checkout_index(checkouts, finished_callback),
             CheckoutSource::Copy => copy::copy_repo(checkouts, finished_callback),
+            CheckoutSource::Revs => git::checkout_revs(checkouts, finished_callback),
             CheckoutSource::None => {
                 for checkout in checkouts {
                     finished_callback(checkout);
@@ -78,6 +313,7 @@ impl std::fmt::Display for CheckoutSource {
             CheckoutSource::Copy => write!(f, "Copying current directory"),
             CheckoutSource::Index => write!(f, "Checking out index"),
             CheckoutSource::None => write!(f, "Using existing checkouts"),
+            CheckoutSource::Revs => write!(f, "Checking out revisions"),
         }
     }
 }
@@ -85,22 +321,136 @@ impl std::fmt::Display for CheckoutSource {
 /// A checkout represents a ready-to-go copy of the repository
 /// with relevant metadata (e.g. the toolchain it represents)
 pub struct Checkout {
+    /// Unique among the run's checkouts, counting from 0.
+    index: usize,
     toolchain: String,
+    rev: Option<Rev>,
     working_dir: PathBuf,
+    /// The log of stdout and stderr together.
     output: PathBuf,
+    stdout_log: PathBuf,
+    stderr_log: PathBuf,
+    /// Where steps can report their progress, as `PANDO_PROGRESS`.
+    progress_file: PathBuf,
     progress: ProgressBar,
+    /// Compiler diagnostics, if any steps collected them.
+    diagnostics: Mutex<Option<Diagnostics>>,
+    /// Results of each test run, parsed from the output.
+    tests: Mutex<Vec<TestResult>>,
+    /// How running the job went.
+    outcome: Mutex<Outcome>,
+    /// Set to stop the checkout, e.g. by fail-fast.
+    stop: AtomicBool,
+    /// Each attempt at running the job, with retries.
+    attempts: Mutex<Vec<Attempt>>,
     // TODO: allowed to fail?
 }
 
+impl Checkout {
+    /// The toolchain, and revision if any, e.g. `stable@1a2b3c4d`.
+    fn name(&self) -> String {
+        checkout_name(&self.toolchain, self.rev.as_ref())
+    }
+
+    /// Give up on this checkout, because it couldn't be made.
+    fn checkout_failed(&self, message: String) {
+        self.progress.finish_with_message(&message);
+        let mut outcome = self.outcome.lock().unwrap();
+        outcome.status = Status::Error;
+        outcome.error = Some(message);
+    }
+
+    /// Give up on this checkout, because the run was cancelled before it started.
+    fn cancelled(&self) {
+        self.progress.finish_with_message("cancelled");
+        self.outcome.lock().unwrap().status = Status::Cancelled;
+    }
+
+    /// Give up on this checkout before it started, because of fail-fast.
+    fn skipped(&self) {
+        self.progress.finish_with_message("skipped");
+        self.outcome.lock().unwrap().status = Status::Skipped;
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+}
+
+/// Stop the checkouts that fail-fast gives up on, once `failed` has failed.
+fn stop_others(checkouts: &[Checkout], failed: &Checkout, mode: FailFast) {
+    for checkout in checkouts {
+        if mode == FailFast::All || checkout.toolchain == failed.toolchain {
+            checkout.stop.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+fn checkout_name(toolchain: &str, rev: Option<&Rev>) -> String {
+    match rev {
+        Some(rev) => format!("{}@{}", toolchain, rev.short_id()),
+        None => toolchain.to_string(),
+    }
+}
+
+/// Where all checkouts (and results) are kept.
+fn pando_dir(cargo_metadata: &CargoMetadata) -> PathBuf {
+    Path::new(&cargo_metadata.target_directory).join("pando")
+}
+
 impl Program {
-    fn run(self) -> Result<(), Error> {
+    fn all_checkouts(&self) -> PathBuf {
+        pando_dir(&self.cargo_metadata)
+    }
+
+    fn compiler_cache(&self) -> Option<&CompilerCache> {
+        match &self.action {
+            Action::Run(job) => job.compiler_cache.as_ref(),
+            Action::Print(_) | Action::All { .. } => None,
+        }
+    }
+
+    fn run(mut self) -> Result<(), Error> {
         if self.toolchains.is_empty() {
             bail!("no toolchains found");
         }
 
+        // each run keeps its logs and results in its own directory
+        let run = match self.action {
+            Action::Run(_) => Some(Run::start(
+                &self.all_checkouts(),
+                self.command_line.clone(),
+                git::head_id(),
+                self.toolchains.clone(),
+                self.revs.clone(),
+            )?),
+            Action::Print(_) | Action::All { .. } => None,
+        };
+        if let (Action::Run(job), Some(run)) = (&mut self.action, &run) {
+            job.run_id = Some(run.info.id.clone());
+        }
+
+        // every toolchain against every revision, if any were given
+        let dimensions = self
+            .toolchains
+            .iter()
+            .flat_map(|toolchain| {
+                let revs = if self.revs.is_empty() {
+                    vec![None]
+                } else {
+                    self.revs.iter().map(Some).collect()
+                };
+                revs.into_iter().map(move |rev| (toolchain, rev))
+            })
+            .collect::<Vec<_>>();
+
         let (checkouts, multi_handle) = {
             let style = {
-                let longest_tchain_name = self.toolchains.iter().map(String::len).max().unwrap();
+                let longest_tchain_name = dimensions
+                    .iter()
+                    .map(|(toolchain, rev)| checkout_name(toolchain, *rev).len())
+                    .max()
+                    .unwrap();
 
                 let template = format!(
                     "{{prefix:<{}}} {{pos}}/{{len}} {{bar}} {{elapsed_precise}} {{msg}} ",
@@ -112,33 +462,55 @@ impl Program {
 
             let multi = MultiProgress::new();
 
-            if !self.action.uses_progress_bars() {
+            if let Action::Print(_) = self.action {
+                multi.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+            } else if !self.show_progress {
                 multi.set_draw_target(indicatif::ProgressDrawTarget::hidden());
             }
 
-            let all_checkouts = Path::new(&self.cargo_metadata.target_directory).join("pando");
+            let all_checkouts = self.all_checkouts();
 
-            let checkouts = self
-                .toolchains
-                .iter()
-                .cloned()
-                .map(|toolchain| {
-                    // 0: waiting for checkout
-                    // 1: checked out, waiting on test
-                    // 2: testing
-                    // 3: done
-                    let progress = multi.add(ProgressBar::new(3));
+            let checkouts = dimensions
+                .into_iter()
+                .enumerate()
+                .map(|(index, (toolchain, rev))| {
+                    let name = checkout_name(toolchain, rev);
+                    let progress = multi.add(ProgressBar::new(1));
                     progress.set_style(style.clone());
-                    progress.set_prefix(&toolchain);
+                    progress.set_prefix(&name);
                     progress.set_message("waiting to be copied");
 
-                    let checkout = all_checkouts.join(&toolchain);
+                    let checkout = all_checkouts.join(&name);
+                    let (output, stdout_log, stderr_log, progress_file) = match &run {
+                        Some(run) => (
+                            run.log(&name),
+                            run.stream_log(&name, "stdout"),
+                            run.stream_log(&name, "stderr"),
+                            run.progress_file(&name),
+                        ),
+                        None => (
+                            checkout.join("output"),
+                            checkout.join("stdout"),
+                            checkout.join("stderr"),
+                            checkout.join("progress"),
+                        ),
+                    };
 
                     Checkout {
-                        toolchain,
+                        index,
+                        toolchain: toolchain.clone(),
+                        rev: rev.cloned(),
                         working_dir: checkout.join("working_dir"),
-                        output: checkout.join("output"),
-                        progress: progress.into(),
+                        output,
+                        stdout_log,
+                        stderr_log,
+                        progress_file,
+                        progress,
+                        diagnostics: Mutex::new(None),
+                        tests: Mutex::new(Vec::new()),
+                        outcome: Mutex::new(Outcome::default()),
+                        stop: AtomicBool::new(false),
+                        attempts: Mutex::new(Vec::new()),
                     }
                 })
                 .collect::<Vec<Checkout>>();
@@ -150,29 +522,30 @@ impl Program {
             (checkouts, multi_handle)
         };
 
-        let success = if !self.action.uses_workers() {
-            let print_checkout_name = |checkout: &Checkout| {
-                println!("{}\t{}", checkout.toolchain, checkout.working_dir.display());
-                checkout.progress.finish();
-            };
-            self.checkout_source
-                .do_checkout(&checkouts, print_checkout_name)?
-        } else {
+        let cache_before = self.compiler_cache().and_then(CompilerCache::stats);
+
+        let success = if let Action::Run(job) = &self.action {
             // Determine worker count based on number of intended checkouts,
             // type of action, job limit specified for the action, and
             // number of CPU cores.
-            let worker_count = std::cmp::min(
-                checkouts.len(),
-                self.action.job_count().unwrap_or_else(num_cpus::get),
-            );
+            let worker_count =
+                std::cmp::min(checkouts.len(), self.jobs.unwrap_or_else(num_cpus::get));
 
+            if let Some(run) = &run {
+                eprintln!("Run {}, logging to {}.", run.info.id, run.dir.display());
+            }
             eprintln!("Using {} workers. {}.", worker_count, self.checkout_source);
 
+            cancel::install_handler();
+            let throttle = Throttle::new(self.limits);
+
             scope(|scope| -> Result<bool, Error> {
                 let (tx, rx) = bounded::<&Checkout>(checkouts.len());
 
-                let actn = &self.action;
                 // spawn workers
+                let checkouts = &checkouts;
+                let fail_fast = self.fail_fast;
+                let throttle = &throttle;
                 let worker_handles = (0..worker_count)
                     .map(|i| {
                         let rx = rx.clone();
@@ -180,31 +553,160 @@ impl Program {
                             .builder()
                             .name(format!("worker {}", i))
                             .spawn(move |scope| -> bool {
-                                rx.iter()
-                                    .map(|checkout| run_cmd(scope, &checkout, actn))
-                                    .fold(true, |x, y| x && y)
+                                let mut success = true;
+                                for checkout in rx.iter() {
+                                    let _running = throttle.start(&checkout.progress);
+                                    if cancel::is_cancelled() {
+                                        checkout.cancelled();
+                                        success = false;
+                                    } else if checkout.is_stopped() {
+                                        checkout.skipped();
+                                        success = false;
+                                    } else if !run_cmd(scope, checkout, job) {
+                                        success = false;
+                                        if let Some(mode) = fail_fast {
+                                            stop_others(checkouts, checkout, mode);
+                                        }
+                                    }
+                                }
+                                success
                             })
                             .with_context(|_| format!("failed to spawn worker {}", i))
                     })
                     .collect::<Result<Vec<ScopedJoinHandle<'_, bool>>, _>>()?;
 
-                // do checkout and send to workers
-                let checkout_success = self
-                    .checkout_source
-                    .do_checkout(&checkouts, move |checkout| tx.send(checkout).unwrap())?;
+                // do checkout and send to workers, until cancelled
+                // checkouts stopped by fail-fast aren't made at all
+                let checkout_success = self.checkout_source.do_checkout(
+                    checkouts
+                        .iter()
+                        .take_while(|_| !cancel::is_cancelled())
+                        .filter(|checkout| !checkout.is_stopped()),
+                    move |checkout| tx.send(checkout).unwrap(),
+                )?;
 
-                Ok(checkout_success
-                    && worker_handles
-                        .into_iter()
-                        .map(|x| x.join().unwrap())
-                        .fold(true, |x, y| x && y))
+                let mut success = checkout_success;
+                for handle in worker_handles {
+                    success &= handle.join().unwrap();
+                }
+                Ok(success)
             })
             .map_err(|_| format_err!("panicked"))??
+        } else if let Action::All { .. } = self.action {
+            self.checkout_source.do_checkout(&checkouts, |checkout| {
+                checkout.progress.finish_with_message("ready")
+            })?
+        } else if let Action::Print(format) = self.action {
+            let mut count = 0;
+            let print_checkout = |checkout: &Checkout| {
+                print::print_checkout(checkout, format, count);
+                count += 1;
+                checkout.progress.finish();
+            };
+            self.checkout_source
+                .do_checkout(&checkouts, print_checkout)?
+        } else {
+            unreachable!("every action is handled")
         };
 
+        // finish off the checkouts that were never sent to a worker
+        let cancelled = cancel::is_cancelled();
+        for checkout in &checkouts {
+            if checkout.outcome.lock().unwrap().status != Status::NotRun {
+                continue;
+            }
+            if cancelled {
+                checkout.cancelled();
+            } else if checkout.is_stopped() {
+                checkout.skipped();
+            }
+        }
+
         multi_handle.join().unwrap();
 
-        if !success {
+        if let Action::All { utility, args } = &self.action {
+            if !success {
+                bail!(
+                    "not every checkout could be prepared, so {} wasn't run",
+                    utility
+                );
+            }
+            let manifest = self.all_checkouts().join(all::MANIFEST_FILE);
+            all::write_manifest(&checkouts, &manifest)?;
+            let status = all::command(utility, args, &checkouts, &manifest)
+                .status()
+                .with_context(|_| format!("error running {}", utility))?;
+            if !status.success() {
+                std::process::exit(status.code().unwrap_or(1));
+            }
+            return Ok(());
+        }
+
+        if let Some(run) = &run {
+            let mut results = RunResults::new(&checkouts);
+            results.cache = match (cache_before, self.compiler_cache()) {
+                (Some(before), Some(cache)) => cache.stats().map(|after| after.since(before)),
+                _ => None,
+            };
+            results.save(&run.results_file())?;
+            results.save(&self.all_checkouts().join(RESULTS_FILE))?;
+            history::prune(&self.all_checkouts(), self.keep_runs)?;
+
+            if let Some(path) = &self.junit {
+                junit::write_junit(&results, path)?;
+            }
+            if let Some(path) = &self.summary_json {
+                results.save(path)?;
+            }
+            match self.format {
+                // the progress bars already show how each checkout went, unless cancelled
+                OutputFormat::Human if cancelled => {
+                    eprintln!("Cancelled. Partial results:");
+                    results.print();
+                }
+                OutputFormat::Human => {
+                    if let Some(cache) = &results.cache {
+                        eprintln!("Compiler cache: {}.", cache);
+                    }
+                }
+                OutputFormat::Json => results.print_json()?,
+                OutputFormat::Markdown => print!("{}", markdown::render(&results)),
+                OutputFormat::Tap => print!(
+                    "{}",
+                    tap::render(&results, self.tap_tests, self.tap_log_lines)
+                ),
+            }
+        }
+
+        for checkout in &checkouts {
+            if let Some(failures) = Failures::new(&checkout.tests.lock().unwrap()) {
+                eprintln!(
+                    "{} on {}: {}",
+                    failures.count(),
+                    checkout.name(),
+                    failures.names()
+                );
+            }
+        }
+
+        if self.print_diagnostics || self.diagnostics_json.is_some() {
+            let collected = checkouts
+                .iter()
+                .map(|checkout| checkout.diagnostics.lock().unwrap().take())
+                .collect::<Vec<_>>();
+            let report = DiagnosticsReport::new(&checkouts, &collected);
+
+            if self.print_diagnostics {
+                report.print();
+            }
+            if let Some(path) = &self.diagnostics_json {
+                report.write_json(path)?;
+            }
+        }
+
+        if cancelled {
+            std::process::exit(130);
+        } else if !success {
             std::process::exit(1);
         } else {
             Ok(())
@@ -212,18 +714,87 @@ impl Program {
     }
 }
 
+/// Print results in the given format, for reporting on past runs.
+/// The format the results are printed in, given --format and --report.
+fn output_format(
+    format: OutputFormat,
+    report: Option<ReportFormat>,
+) -> Result<OutputFormat, Error> {
+    match (format, report) {
+        (format, None) => Ok(format),
+        (OutputFormat::Human, Some(ReportFormat::Markdown))
+        | (OutputFormat::Markdown, Some(ReportFormat::Markdown)) => Ok(OutputFormat::Markdown),
+        (format, Some(_)) => bail!(
+            "--report can't be used with --format {}, since both print to stdout",
+            format!("{:?}", format).to_lowercase()
+        ),
+    }
+}
+
+fn print_results(results: &RunResults, format: OutputFormat, opts: &Opts) -> Result<(), Error> {
+    match format {
+        OutputFormat::Human => results.print(),
+        OutputFormat::Json => results.print_json()?,
+        OutputFormat::Markdown => print!("{}", markdown::render(results)),
+        OutputFormat::Tap => print!(
+            "{}",
+            tap::render(results, opts.tap_tests, opts.tap_log_lines)
+        ),
+    }
+    Ok(())
+}
+
 fn main() -> Result<(), Error> {
-    let args = std::env::args().enumerate().filter_map(|(i, arg)| {
-        // handle being invoked as a cargo subcommand (will have pando passed as arg 1)
-        // as well as on our own (e.g. cargo run in this dir, no extra arg 1)
-        if i == 1 && arg == "pando" {
-            None
+    let args = std::env::args()
+        .enumerate()
+        .filter_map(|(i, arg)| {
+            // handle being invoked as a cargo subcommand (will have pando passed as arg 1)
+            // as well as on our own (e.g. cargo run in this dir, no extra arg 1)
+            if i == 1 && arg == "pando" {
+                None
+            } else {
+                Some(arg)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let command_line = ["cargo", "pando"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .chain(args.iter().skip(1).cloned())
+        .collect();
+    let opts = Opts::from_iter(args);
+
+    if let Some(ActionOpt::Report { diff, format }) = opts.action {
+        let results =
+            RunResults::load(&pando_dir(&cargo::get_cargo_metadata()).join(RESULTS_FILE))?;
+        if diff {
+            results.print_diff();
         } else {
-            Some(arg)
+            print_results(&results, output_format(format, opts.report)?, &opts)?;
         }
-    });
+        return Ok(());
+    }
 
-    let opts = Opts::from_iter(args);
+    if let Some(ActionOpt::History) = opts.action {
+        return history::print_history(&pando_dir(&cargo::get_cargo_metadata()));
+    }
+
+    if let Some(ActionOpt::Show { run_id, format }) = &opts.action {
+        let format = output_format(*format, opts.report)?;
+        let run = Run::load(&pando_dir(&cargo::get_cargo_metadata()), run_id)?;
+        let results = run.results()?;
+        if format == OutputFormat::Human {
+            run.print_info();
+            println!();
+        }
+        match results {
+            Some(results) => print_results(&results, format, &opts)?,
+            None if format == OutputFormat::Human => println!("This run never finished."),
+            None => bail!("run {} never finished, so it has no results", run_id),
+        }
+        return Ok(());
+    }
 
-    opts_to_program(opts)?.run()
+    opts_to_program(opts, command_line)?.run()
 }
@@ -0,0 +1,99 @@
+//! Markdown output, for pasting into pull requests.
+use super::report::{log_tail, CheckoutResults, RunResults, Status};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// How many lines of a failing checkout's log to include.
+const TAIL_LINES: usize = 30;
+
+/// Render the results as a table of toolchains by revision,
+/// followed by the end of the log of each failing checkout.
+pub fn render(results: &RunResults) -> String {
+    let mut out = String::new();
+
+    // toolchains in the order they were given, each with a cell per column
+    let mut columns = Vec::<String>::new();
+    let mut rows = Vec::<(&str, BTreeMap<String, &CheckoutResults>)>::new();
+    for checkout in &results.checkouts {
+        let column = match &checkout.rev {
+            Some(rev) => format!("{} (`{}`)", rev.spec, rev.short_id()),
+            None => "result".to_string(),
+        };
+        if !columns.contains(&column) {
+            columns.push(column.clone());
+        }
+
+        match rows
+            .iter_mut()
+            .find(|(toolchain, _)| *toolchain == checkout.toolchain)
+        {
+            Some((_, cells)) => {
+                cells.insert(column, checkout);
+            }
+            None => {
+                let mut cells = BTreeMap::new();
+                cells.insert(column, checkout);
+                rows.push((&checkout.toolchain, cells));
+            }
+        }
+    }
+
+    writeln!(out, "| toolchain | {} |", columns.join(" | ")).unwrap();
+    writeln!(out, "|---|{}", "---|".repeat(columns.len())).unwrap();
+    for (toolchain, cells) in &rows {
+        let cells = columns
+            .iter()
+            .map(|column| cells.get(column).map_or(String::new(), |c| cell(c)))
+            .collect::<Vec<_>>();
+        writeln!(out, "| {} | {} |", toolchain, cells.join(" | ")).unwrap();
+    }
+    if let Some(cache) = &results.cache {
+        writeln!(out, "\nCompiler cache: {}", cache).unwrap();
+    }
+
+    for checkout in results.checkouts.iter().filter(|c| !c.success()) {
+        let tail = log_tail(&checkout.output, TAIL_LINES);
+        let fence = fence_for(&tail);
+
+        writeln!(out).unwrap();
+        writeln!(out, "<details>").unwrap();
+        writeln!(
+            out,
+            "<summary>❌ {}: {}</summary>\n",
+            checkout.name(),
+            checkout.describe()
+        )
+        .unwrap();
+        writeln!(out, "{}\n{}\n{}", fence, tail, fence).unwrap();
+        writeln!(out, "</details>").unwrap();
+    }
+
+    out
+}
+
+fn cell(checkout: &CheckoutResults) -> String {
+    let mark = match checkout.outcome.status {
+        Status::Success => "✅",
+        Status::TimedOut => "⏱️",
+        Status::Cancelled => "🚫",
+        Status::Skipped => "⏭️",
+        Status::Flaky => "⚠️",
+        _ => "❌",
+    };
+    let duration = checkout
+        .outcome
+        .duration_secs
+        .map_or(String::new(), |secs| format!(" {:.1}s", secs));
+    format!(
+        "{}{} [log](<{}>)",
+        mark,
+        duration,
+        checkout.output.display()
+    )
+}
+
+/// A code fence longer than any run of backticks in the text.
+fn fence_for(text: &str) -> String {
+    let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    "`".repeat(longest.max(2) + 1)
+}
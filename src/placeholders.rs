@@ -0,0 +1,118 @@
+//! Substituting details of a checkout into a step's arguments, e.g. `{toolchain}`.
+//!
+//! Placeholders can appear anywhere in an argument. `{{` and `}}` are literal braces,
+//! and braces around anything else are left alone.
+use super::action::Job;
+use super::Checkout;
+
+pub struct Placeholders<'a> {
+    pub checkout: &'a Checkout,
+    pub job: &'a Job,
+}
+
+impl Placeholders<'_> {
+    pub fn substitute(&self, arg: &str) -> String {
+        substitute(arg, |name| self.value(name))
+    }
+
+    /// The value of the named placeholder, or `None` if there's no such placeholder.
+    fn value(&self, name: &str) -> Option<String> {
+        let checkout = self.checkout;
+        let value = match name {
+            // just `{}` is the toolchain, as it always has been
+            "" | "toolchain" => checkout.toolchain.clone(),
+            "rev" => checkout
+                .rev
+                .as_ref()
+                .map(|rev| rev.id.clone())
+                .unwrap_or_default(),
+            "short_rev" => checkout
+                .rev
+                .as_ref()
+                .map(|rev| rev.short_id().to_string())
+                .unwrap_or_default(),
+            "target" => self.job.target().unwrap_or_default(),
+            "features" => self.job.features(),
+            "dir" => checkout.working_dir.display().to_string(),
+            "orig_dir" => self.job.original_dir.display().to_string(),
+            "index" => checkout.index.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+/// Replace each `{name}` in `arg` for which `value` gives a value.
+pub fn substitute(arg: &str, mut value: impl FnMut(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(arg.len());
+    let mut rest = arg;
+    while let Some(i) = rest.find(|c| c == '{' || c == '}') {
+        out.push_str(&rest[..i]);
+        let tail = &rest[i..];
+
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            out.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+        if tail.starts_with('{') {
+            if let Some(end) = tail.find('}') {
+                if let Some(value) = value(&tail[1..end]) {
+                    out.push_str(&value);
+                    rest = &tail[end + 1..];
+                    continue;
+                }
+            }
+        }
+        out.push_str(&tail[..1]);
+        rest = &tail[1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(name: &str) -> Option<String> {
+        match name {
+            "" | "toolchain" => Some("stable".to_string()),
+            "dir" => Some("/a/b".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn substitutes_anywhere() {
+        assert_eq!(substitute("{toolchain}", value), "stable");
+        assert_eq!(substitute("{}", value), "stable");
+        assert_eq!(
+            substitute("--out={dir}/{}.txt", value),
+            "--out=/a/b/stable.txt"
+        );
+        assert_eq!(substitute("no placeholders", value), "no placeholders");
+    }
+
+    #[test]
+    fn escaped_braces() {
+        assert_eq!(substitute("{{toolchain}}", value), "{toolchain}");
+        assert_eq!(substitute("{{{toolchain}}}", value), "{stable}");
+        assert_eq!(substitute("}}", value), "}");
+    }
+
+    #[test]
+    fn unterminated() {
+        assert_eq!(substitute("{toolchain", value), "{toolchain");
+        assert_eq!(substitute("a{", value), "a{");
+        assert_eq!(substitute("{dir", value), "{dir");
+        assert_eq!(substitute("a}b", value), "a}b");
+    }
+
+    #[test]
+    fn unknown_names_left_alone() {
+        assert_eq!(substitute("{nope}", value), "{nope}");
+        assert_eq!(substitute("{nope}{dir}", value), "{nope}/a/b");
+        assert_eq!(substitute(r#"{"a": 1}"#, value), r#"{"a": 1}"#);
+    }
+}
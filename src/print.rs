@@ -0,0 +1,49 @@
+//! Printing each checkout as it's ready, for the print action, in a format for scripts.
+use super::cli::PrintFormat;
+use super::Checkout;
+use serde_json::json;
+
+/// Print the checkout in the given format.
+///
+/// For the shell format, `count` is how many checkouts have been printed before it.
+pub fn print_checkout(checkout: &Checkout, format: PrintFormat, count: usize) {
+    let name = checkout.name();
+    let dir = checkout.working_dir.display().to_string();
+    match format {
+        PrintFormat::Tsv => println!("{}\t{}", name, dir),
+        PrintFormat::Null => print!("{}\0{}\0", name, dir),
+        PrintFormat::JsonLines => {
+            let line = json!({
+                "name": name,
+                "index": checkout.index,
+                "toolchain": checkout.toolchain,
+                "rev": checkout.rev,
+                "dir": checkout.working_dir,
+            });
+            println!("{}", line);
+        }
+        PrintFormat::Shell => {
+            let rev = checkout.rev.as_ref();
+            let vars = [
+                ("NAME", name),
+                ("INDEX", checkout.index.to_string()),
+                ("TOOLCHAIN", checkout.toolchain.clone()),
+                ("REV", rev.map(|rev| rev.id.clone()).unwrap_or_default()),
+                (
+                    "REV_SPEC",
+                    rev.map(|rev| rev.spec.clone()).unwrap_or_default(),
+                ),
+                ("DIR", dir),
+            ];
+            for (var, value) in &vars {
+                println!("export PANDO_{}_{}={}", count, var, shell_quote(value));
+            }
+            println!("export PANDO_COUNT={}", count + 1);
+        }
+    }
+}
+
+/// Quote a value for a POSIX shell, e.g. `'it'\''s'`.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
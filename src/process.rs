@@ -0,0 +1,169 @@
+//! Supervising the processes that steps run: process groups, timeouts and signals.
+use super::cancel;
+use std::io;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// How long a process gets between SIGQUIT and SIGKILL, to dump backtraces.
+const QUIT_GRACE: Duration = Duration::from_secs(5);
+
+/// How long a process gets between SIGTERM and SIGKILL, when the run is cancelled.
+const CANCEL_GRACE: Duration = Duration::from_secs(5);
+
+/// How often to check for the deadline or cancellation while waiting.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Run the command in its own process group, so it can be signalled along with
+/// everything it starts, and so Ctrl-C is left for pando to handle.
+#[cfg(unix)]
+pub fn own_process_group(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setpgid(0, 0) == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub fn own_process_group(_cmd: &mut Command) {}
+
+/// Why a supervised process was stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stopped {
+    TimedOut,
+    Cancelled,
+}
+
+/// Watches a child process, signalling its process group once its deadline passes
+/// or the run (or just its checkout) is cancelled.
+pub struct Supervisor<'a> {
+    /// When to send the next signal, if any.
+    deadline: Option<Instant>,
+    /// On timeout, send SIGQUIT before SIGKILL after a grace period.
+    quit_first: bool,
+    /// Set to cancel just this child, e.g. by fail-fast.
+    cancel: &'a AtomicBool,
+    stopped: Option<Stopped>,
+}
+
+impl<'a> Supervisor<'a> {
+    pub fn new(
+        deadline: Option<Instant>,
+        quit_first: bool,
+        cancel: &'a AtomicBool,
+    ) -> Supervisor<'a> {
+        Supervisor {
+            deadline,
+            quit_first,
+            cancel,
+            stopped: None,
+        }
+    }
+
+    /// Why the child was stopped, if it was.
+    pub fn stopped(&self) -> Option<Stopped> {
+        self.stopped
+    }
+
+    /// How long to wait before calling `check` again.
+    pub fn poll_interval(&self) -> Duration {
+        match self.deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if deadline > now {
+                    (deadline - now).min(POLL_INTERVAL)
+                } else {
+                    Duration::from_secs(0)
+                }
+            }
+            None => POLL_INTERVAL,
+        }
+    }
+
+    /// Signal the child if the run was cancelled or its deadline has passed.
+    pub fn check(&mut self, child: &mut Child) {
+        let cancelled = cancel::is_cancelled() || self.cancel.load(Ordering::SeqCst);
+        if cancelled && self.stopped.is_none() {
+            self.stopped = Some(Stopped::Cancelled);
+            self.quit_first = false;
+            self.deadline = Some(Instant::now() + CANCEL_GRACE);
+            terminate(child);
+            return;
+        }
+
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => {}
+            _ => return,
+        }
+
+        self.stopped.get_or_insert(Stopped::TimedOut);
+        if self.quit_first {
+            self.quit_first = false;
+            self.deadline = Some(Instant::now() + QUIT_GRACE);
+            quit(child);
+        } else {
+            self.deadline = None;
+            kill(child);
+        }
+    }
+
+    /// Kill the child right away, e.g. when its output can't be logged.
+    pub fn kill(&mut self, child: &mut Child) {
+        self.deadline = None;
+        kill(child);
+    }
+
+    /// Wait for the child to exit, signalling it as needed.
+    pub fn wait(&mut self, child: &mut Child) -> io::Result<ExitStatus> {
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+            self.check(child);
+            std::thread::sleep(self.poll_interval());
+        }
+    }
+}
+
+#[cfg(unix)]
+fn terminate(child: &mut Child) {
+    signal_group(child, libc::SIGTERM);
+}
+
+#[cfg(not(unix))]
+fn terminate(child: &mut Child) {
+    let _ = child.kill();
+}
+
+#[cfg(unix)]
+fn quit(child: &mut Child) {
+    signal_group(child, libc::SIGQUIT);
+}
+
+#[cfg(not(unix))]
+fn quit(_child: &mut Child) {}
+
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    signal_group(child, libc::SIGKILL);
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// Send a signal to the child's process group.
+#[cfg(unix)]
+fn signal_group(child: &Child, signal: std::os::raw::c_int) {
+    // the group was created with the child, so has the same ID
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), signal);
+    }
+}
@@ -0,0 +1,181 @@
+//! Letting steps report their own progress, by appending lines to the file named by
+//! `PANDO_PROGRESS`:
+//!
+//! - `stage 2/5 compiling` moves the checkout's progress bar to 2 of 5,
+//!   with an optional message.
+//! - `message foo`, or any other line, sets the progress bar's message.
+//!
+//! If the file is truncated, it's read again from the start.
+use failure::{Error, ResultExt};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// A line of the progress protocol.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Update<'a> {
+    Stage {
+        stage: u64,
+        stages: u64,
+        message: &'a str,
+    },
+    Message(&'a str),
+}
+
+impl<'a> Update<'a> {
+    pub fn parse(line: &'a str) -> Update<'a> {
+        let (keyword, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim_start()),
+            None => (line, ""),
+        };
+        match keyword {
+            "stage" => {
+                let (count, message) = match rest.find(char::is_whitespace) {
+                    Some(i) => (&rest[..i], rest[i..].trim_start()),
+                    None => (rest, ""),
+                };
+                let mut count = count.splitn(2, '/').map(str::parse::<u64>);
+                match (count.next(), count.next()) {
+                    (Some(Ok(stage)), Some(Ok(stages))) => Update::Stage {
+                        stage,
+                        stages,
+                        message,
+                    },
+                    _ => Update::Message(line),
+                }
+            }
+            "message" => Update::Message(rest),
+            _ => Update::Message(line),
+        }
+    }
+}
+
+/// A step's progress file, read as it's written to.
+pub struct ProgressFile {
+    path: PathBuf,
+    file: File,
+    /// The start of a line that hasn't been finished yet.
+    partial: Vec<u8>,
+}
+
+impl ProgressFile {
+    /// Create the file, or empty it if it's left over from a previous step.
+    pub fn create(path: &Path) -> Result<ProgressFile, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .with_context(|_| format!("error creating progress file {}", path.display()))?;
+        Ok(ProgressFile {
+            path: path.to_path_buf(),
+            file,
+            partial: Vec::new(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Pass each line written since the last call to `f`.
+    ///
+    /// Errors are ignored, since progress is only informational.
+    pub fn read_new(&mut self, mut f: impl FnMut(&str)) {
+        self.rewind_if_truncated();
+        if self.file.read_to_end(&mut self.partial).is_err() {
+            return;
+        }
+        let end = match self.partial.iter().rposition(|b| *b == b'\n') {
+            Some(end) => end,
+            None => return,
+        };
+        let rest = self.partial.split_off(end + 1);
+        for line in String::from_utf8_lossy(&self.partial).lines() {
+            let line = line.trim();
+            if !line.is_empty() {
+                f(line);
+            }
+        }
+        self.partial = rest;
+    }
+
+    /// Go back to the start if the file has shrunk since it was last read,
+    /// e.g. if a step rewrote it instead of appending to it.
+    fn rewind_if_truncated(&mut self) {
+        let position = match self.file.seek(SeekFrom::Current(0)) {
+            Ok(position) => position,
+            Err(_) => return,
+        };
+        let len = match self.file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return,
+        };
+        if len < position && self.file.seek(SeekFrom::Start(0)).is_ok() {
+            self.partial.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+
+    #[test]
+    fn stages() {
+        assert_eq!(
+            Update::parse("stage 2/5 compiling  tests"),
+            Update::Stage {
+                stage: 2,
+                stages: 5,
+                message: "compiling  tests"
+            }
+        );
+        assert_eq!(
+            Update::parse("stage 2/5"),
+            Update::Stage {
+                stage: 2,
+                stages: 5,
+                message: ""
+            }
+        );
+    }
+
+    #[test]
+    fn bad_stages() {
+        assert_eq!(
+            Update::parse("stage x/5 foo"),
+            Update::Message("stage x/5 foo")
+        );
+        assert_eq!(Update::parse("stage 2"), Update::Message("stage 2"));
+        assert_eq!(Update::parse("stage"), Update::Message("stage"));
+    }
+
+    #[test]
+    fn messages() {
+        assert_eq!(
+            Update::parse("message  hi there"),
+            Update::Message("hi there")
+        );
+        assert_eq!(Update::parse("message"), Update::Message(""));
+        assert_eq!(Update::parse("compiling"), Update::Message("compiling"));
+        assert_eq!(Update::parse("stages 2/5"), Update::Message("stages 2/5"));
+    }
+
+    #[test]
+    fn truncated() {
+        let path = std::env::temp_dir().join(format!("pando-progress-{}", std::process::id()));
+        let mut progress = ProgressFile::create(&path).unwrap();
+        let mut lines = Vec::new();
+
+        write(&path, "stage 1/3\nstage 2/3 long message\npart").unwrap();
+        progress.read_new(|line| lines.push(line.to_string()));
+        write(&path, "stage 3/3\n").unwrap();
+        progress.read_new(|line| lines.push(line.to_string()));
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(lines, ["stage 1/3", "stage 2/3 long message", "stage 3/3"]);
+    }
+}
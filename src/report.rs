@@ -0,0 +1,393 @@
+//! Results of the last run, stored so they can be reported on afterwards.
+use super::cache::CacheStats;
+use super::git::Rev;
+use super::libtest::{Failures, TestOutcome, TestResult};
+use super::logs::attempt_log;
+use super::{checkout_name, Checkout};
+use console::style;
+use failure::{Error, ResultExt};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read, File};
+use std::io::stdout;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Where the results of the last run are kept, within `target/pando`.
+pub const RESULTS_FILE: &str = "results.json";
+
+/// A duration in seconds, as reported. Like `Duration::as_secs_f64`, which needs Rust 1.38.
+pub fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+/// The results of every checkout in a run.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunResults {
+    /// Whether every checkout succeeded.
+    pub success: bool,
+    pub checkouts: Vec<CheckoutResults>,
+    /// How the compiler cache did during the run, if one was used and could say.
+    #[serde(default)]
+    pub cache: Option<CacheStats>,
+}
+
+/// The results of a single checkout.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CheckoutResults {
+    pub toolchain: String,
+    pub rev: Option<Rev>,
+    pub working_dir: PathBuf,
+    /// The log of the checkout's stdout and stderr together.
+    pub output: PathBuf,
+    pub stdout_log: Option<PathBuf>,
+    pub stderr_log: Option<PathBuf>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+    pub tests: Vec<TestResult>,
+    /// Every attempt at running the job, if it was retried.
+    #[serde(default)]
+    pub attempts: Vec<Attempt>,
+}
+
+/// One attempt at running a checkout's job.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Attempt {
+    pub status: Status,
+    /// The log of this attempt's output.
+    pub output: PathBuf,
+    pub duration_secs: f64,
+    pub failed_tests: Vec<String>,
+}
+
+/// How far a checkout got.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    /// Never run, e.g. because the checkout couldn't be made.
+    NotRun,
+    Success,
+    /// A step exited unsuccessfully.
+    Failure,
+    /// A step couldn't be run at all.
+    Error,
+    /// A step ran for longer than its timeout, and was killed.
+    TimedOut,
+    /// The run was cancelled before the checkout finished.
+    Cancelled,
+    /// Never run, because of --fail-fast.
+    Skipped,
+    /// Failed at first, but succeeded when retried.
+    Flaky,
+}
+
+/// How a checkout's run ended.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Outcome {
+    pub status: Status,
+    /// e.g. `rustc 1.38.0 (625451e37 2019-09-23)`
+    pub rustc_version: Option<String>,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub failed_step: Option<String>,
+    pub error: Option<String>,
+    /// The timeout that was hit, if the status is timed out.
+    pub timeout_secs: Option<f64>,
+    pub duration_secs: Option<f64>,
+    /// Which attempt at running the job this is the outcome of, counting from 1.
+    #[serde(default)]
+    pub attempt: usize,
+    /// The index of the failed step in the job, since names needn't be unique.
+    #[serde(skip)]
+    pub failed_step_index: Option<usize>,
+    /// Whether the failed step failed just because some of its tests did,
+    /// rather than e.g. crashing, so retrying only those tests is enough.
+    #[serde(skip)]
+    pub only_tests_failed: bool,
+}
+
+impl Default for Outcome {
+    fn default() -> Outcome {
+        Outcome {
+            status: Status::NotRun,
+            rustc_version: None,
+            exit_code: None,
+            signal: None,
+            failed_step: None,
+            error: None,
+            timeout_secs: None,
+            duration_secs: None,
+            attempt: 0,
+            failed_step_index: None,
+            only_tests_failed: false,
+        }
+    }
+}
+
+impl CheckoutResults {
+    pub fn name(&self) -> String {
+        checkout_name(&self.toolchain, self.rev.as_ref())
+    }
+
+    /// Whether the checkout succeeded, even if only on a retry.
+    pub fn success(&self) -> bool {
+        self.outcome.status.is_success()
+    }
+
+    /// Whether the checkout was skipped or cancelled, so neither passed nor failed.
+    pub fn skipped(&self) -> bool {
+        self.outcome.status == Status::Skipped || self.outcome.status == Status::Cancelled
+    }
+
+    /// A short description of how the checkout went, e.g. `2 tests failed: a, b`.
+    pub fn describe(&self) -> String {
+        self.outcome.describe(&self.tests)
+    }
+}
+
+impl Status {
+    pub fn is_success(self) -> bool {
+        self == Status::Success || self == Status::Flaky
+    }
+}
+
+impl Outcome {
+    /// A short description of how the checkout went, given its test results.
+    pub fn describe(&self, tests: &[TestResult]) -> String {
+        let step = match &self.failed_step {
+            Some(step) => format!(" in step {}", step),
+            None => String::new(),
+        };
+
+        match self.status {
+            Status::NotRun => "not run".to_string(),
+            Status::Success => "success".to_string(),
+            Status::Cancelled => format!("cancelled{}", step),
+            Status::Skipped => "skipped".to_string(),
+            Status::Flaky => format!("flaky: passed on attempt {}", self.attempt),
+            Status::TimedOut => format!(
+                "timed out{} after {}s",
+                step,
+                self.timeout_secs.unwrap_or_default()
+            ),
+            Status::Error => format!(
+                "error{}: {}",
+                step,
+                self.error.as_ref().map_or("unknown", String::as_str)
+            ),
+            Status::Failure => {
+                let reason = match (Failures::new(tests), self.exit_code, self.signal) {
+                    (Some(failures), _, _) => format!("{}: {}", failures.count(), failures.names()),
+                    (None, Some(code), _) => format!("status {}", code),
+                    (None, None, Some(signal)) => format!("killed by signal {}", signal),
+                    (None, None, None) => "status unknown".to_string(),
+                };
+                format!("failure{}: {}", step, reason)
+            }
+        }
+    }
+}
+
+impl RunResults {
+    pub fn new(checkouts: &[Checkout]) -> RunResults {
+        let checkouts = checkouts
+            .iter()
+            .map(|checkout| {
+                // the logs of the last attempt are the ones that decided the outcome
+                let attempts = checkout.attempts.lock().unwrap().clone();
+                let last = attempts.len();
+                CheckoutResults {
+                    toolchain: checkout.toolchain.clone(),
+                    rev: checkout.rev.clone(),
+                    working_dir: checkout.working_dir.clone(),
+                    output: attempt_log(&checkout.output, last),
+                    stdout_log: Some(attempt_log(&checkout.stdout_log, last)),
+                    stderr_log: Some(attempt_log(&checkout.stderr_log, last)),
+                    outcome: checkout.outcome.lock().unwrap().clone(),
+                    tests: checkout.tests.lock().unwrap().clone(),
+                    attempts,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        RunResults {
+            success: checkouts.iter().all(CheckoutResults::success),
+            checkouts,
+            cache: None,
+        }
+    }
+
+    /// Write the results as JSON to the given path.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir).with_context(|_| format!("could not create {}", dir.display()))?;
+        }
+        let file =
+            File::create(path).with_context(|_| format!("could not create {}", path.display()))?;
+        serde_json::to_writer_pretty(file, self)
+            .with_context(|_| format!("could not write results to {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<RunResults, Error> {
+        let file = File::open(path).with_context(|_| {
+            format!(
+                "could not open {}. Has pando been run here yet?",
+                path.display()
+            )
+        })?;
+        let results = serde_json::from_reader(file)
+            .with_context(|_| format!("{} was malformed", path.display()))?;
+        Ok(results)
+    }
+
+    /// Print the results as JSON to stdout.
+    pub fn print_json(&self) -> Result<(), Error> {
+        let stdout = stdout();
+        serde_json::to_writer_pretty(stdout.lock(), self)
+            .context("could not write results to stdout")?;
+        println!();
+        Ok(())
+    }
+
+    /// Print whether each checkout succeeded, and which tests failed.
+    pub fn print(&self) {
+        for checkout in &self.checkouts {
+            let status = match checkout.outcome.status {
+                Status::Success => style(checkout.describe()).green(),
+                Status::Flaky => style(checkout.describe()).yellow(),
+                _ => style(checkout.describe()).red(),
+            };
+            println!("{}\t{}", checkout.name(), status);
+        }
+        if let Some(cache) = &self.cache {
+            println!("compiler cache: {}", cache);
+        }
+    }
+
+    /// Print a matrix of only the tests whose outcome differs between checkouts.
+    ///
+    /// Checkouts without any test results (e.g. because they failed to build)
+    /// are left out, instead of making every test look divergent.
+    pub fn print_diff(&self) {
+        let (tested, untested): (Vec<_>, Vec<_>) = self
+            .checkouts
+            .iter()
+            .partition(|checkout| !checkout.tests.is_empty());
+
+        if !untested.is_empty() {
+            let names = untested.iter().map(|c| c.name()).collect::<Vec<_>>();
+            println!("No test results for {}.", names.join(", "));
+        }
+
+        let mut matrix = BTreeMap::<&str, Vec<Option<TestOutcome>>>::new();
+        for (i, checkout) in tested.iter().enumerate() {
+            for test in &checkout.tests {
+                let outcome = &mut matrix
+                    .entry(&test.name)
+                    .or_insert_with(|| vec![None; tested.len()])[i];
+                // the same name can be in multiple test binaries; any failure wins
+                if *outcome != Some(TestOutcome::Failed) {
+                    *outcome = Some(test.outcome);
+                }
+            }
+        }
+        let matrix = matrix
+            .into_iter()
+            .filter(|(_, outcomes)| outcomes.iter().any(|outcome| *outcome != outcomes[0]))
+            .collect::<BTreeMap<_, _>>();
+
+        if matrix.is_empty() {
+            println!("No tests differ between checkouts.");
+            return;
+        }
+
+        let names = tested.iter().map(|c| c.name()).collect::<Vec<_>>();
+        let test_width = matrix.keys().map(|name| name.len()).max().unwrap_or(0);
+        let widths = names
+            .iter()
+            .map(|name| name.len().max("ignored".len()))
+            .collect::<Vec<_>>();
+
+        print!("{:<width$}", "", width = test_width);
+        for (name, width) in names.iter().zip(&widths) {
+            print!("  {:<width$}", name, width = width);
+        }
+        println!();
+
+        for (test, outcomes) in &matrix {
+            print!("{:<width$}", test, width = test_width);
+            for (outcome, width) in outcomes.iter().zip(&widths) {
+                let cell = format!("{:<width$}", describe(*outcome), width = width);
+                let cell = match outcome {
+                    Some(TestOutcome::Passed) => style(cell).green(),
+                    Some(TestOutcome::Failed) => style(cell).red(),
+                    Some(TestOutcome::Ignored) => style(cell).yellow(),
+                    None => style(cell).dim(),
+                };
+                print!("  {}", cell);
+            }
+            for note in notes(&tested, outcomes) {
+                print!("  ({})", note);
+            }
+            println!();
+        }
+    }
+}
+
+fn describe(outcome: Option<TestOutcome>) -> &'static str {
+    match outcome {
+        Some(TestOutcome::Passed) => "ok",
+        Some(TestOutcome::Failed) => "FAILED",
+        Some(TestOutcome::Ignored) => "ignored",
+        None => "-",
+    }
+}
+
+/// Point out the patterns that are easy to miss in the matrix:
+/// a test failing on just one checkout, or changing between revisions.
+fn notes(checkouts: &[&CheckoutResults], outcomes: &[Option<TestOutcome>]) -> Vec<String> {
+    let mut notes = Vec::new();
+
+    let failing = checkouts
+        .iter()
+        .zip(outcomes)
+        .filter(|(_, outcome)| **outcome == Some(TestOutcome::Failed))
+        .map(|(checkout, _)| checkout.name())
+        .collect::<Vec<_>>();
+    if failing.len() == 1 && checkouts.len() > 2 {
+        notes.push(format!("only fails on {}", failing[0]));
+    }
+
+    let mut by_toolchain = BTreeMap::<&str, Vec<Option<TestOutcome>>>::new();
+    for (checkout, outcome) in checkouts.iter().zip(outcomes) {
+        if checkout.rev.is_some() {
+            by_toolchain
+                .entry(&checkout.toolchain)
+                .or_default()
+                .push(*outcome);
+        }
+    }
+    let differing = by_toolchain
+        .into_iter()
+        .filter(|(_, outcomes)| outcomes.iter().any(|outcome| *outcome != outcomes[0]))
+        .map(|(toolchain, _)| toolchain)
+        .collect::<Vec<_>>();
+    if !differing.is_empty() {
+        notes.push(format!(
+            "differs between revisions on {}",
+            differing.join(", ")
+        ));
+    }
+
+    notes
+}
+
+/// The last `lines` lines of a log, with any invalid UTF-8 replaced.
+pub fn log_tail(path: &Path, lines: usize) -> String {
+    let log = read(path)
+        .map(|log| String::from_utf8_lossy(&log).into_owned())
+        .unwrap_or_default();
+    let all = log.lines().collect::<Vec<_>>();
+    all[all.len().saturating_sub(lines)..].join("\n")
+}
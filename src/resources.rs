@@ -0,0 +1,118 @@
+//! Holding off on starting checkouts while the machine is busy,
+//! judging by its load average and available memory.
+use super::cancel;
+use indicatif::ProgressBar;
+use std::fs::read_to_string;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// How often to check whether there's room for another checkout.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait after starting a checkout before starting another,
+/// since the load average and memory use take a while to reflect it.
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+
+/// When a new checkout shouldn't be started.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// The highest 1 minute load average to start at.
+    pub max_load: Option<f64>,
+    /// The least available memory to start with, in bytes.
+    pub min_memory: Option<u64>,
+}
+
+impl Limits {
+    fn is_set(&self) -> bool {
+        self.max_load.is_some() || self.min_memory.is_some()
+    }
+
+    /// Why a checkout shouldn't be started now, if it shouldn't.
+    ///
+    /// Whatever can't be read (e.g. on systems without `/proc`) doesn't hold anything up.
+    fn exceeded(&self) -> Option<String> {
+        if let (Some(max_load), Some(load)) = (self.max_load, load_average()) {
+            if load > max_load {
+                return Some(format!("load average is {:.2}", load));
+            }
+        }
+        if let (Some(min_memory), Some(memory)) = (self.min_memory, available_memory()) {
+            if memory < min_memory {
+                return Some(format!("{} MiB of memory available", memory >> 20));
+            }
+        }
+        None
+    }
+}
+
+/// Lets checkouts start only while the machine is within the limits.
+pub struct Throttle {
+    limits: Limits,
+    /// When a checkout was last started. Locked while waiting, so checkouts start in order.
+    last_start: Mutex<Option<Instant>>,
+    running: AtomicUsize,
+}
+
+impl Throttle {
+    pub fn new(limits: Limits) -> Throttle {
+        Throttle {
+            limits,
+            last_start: Mutex::new(None),
+            running: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait until there's room for another checkout, or the run is cancelled.
+    ///
+    /// The checkout counts as running until the returned guard is dropped.
+    /// If nothing else is running, it always starts, so a busy machine can't stall the run.
+    pub fn start(&self, progress: &ProgressBar) -> Running<'_> {
+        if self.limits.is_set() {
+            let mut last_start = self.last_start.lock().unwrap();
+            while !cancel::is_cancelled() && self.running.load(Ordering::SeqCst) > 0 {
+                match self.limits.exceeded() {
+                    Some(reason) => progress.set_message(&format!("waiting: {}", reason)),
+                    None if last_start.map_or(true, |time| time.elapsed() >= SETTLE_TIME) => break,
+                    None => {}
+                }
+                sleep(POLL_INTERVAL);
+            }
+            *last_start = Some(Instant::now());
+        }
+
+        self.running.fetch_add(1, Ordering::SeqCst);
+        Running(self)
+    }
+}
+
+/// A checkout that's been started, until it's dropped.
+pub struct Running<'a>(&'a Throttle);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The 1 minute load average, from `/proc/loadavg`.
+fn load_average() -> Option<f64> {
+    read_to_string("/proc/loadavg")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// How much memory can be used without swapping, in bytes, from `/proc/meminfo`.
+fn available_memory() -> Option<u64> {
+    let meminfo = read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    // e.g. `MemAvailable:    8012345 kB`
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
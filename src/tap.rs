@@ -0,0 +1,155 @@
+//! TAP (Test Anything Protocol) output, for TAP harnesses like `prove`.
+use super::libtest::TestOutcome;
+use super::report::{log_tail, CheckoutResults, RunResults};
+use std::fs::read;
+
+/// Render the results as TAP, with a test point per checkout,
+/// or per parsed test if `tests` is set.
+///
+/// Failures get the last `log_lines` lines of their log as diagnostics.
+pub fn render(results: &RunResults, tests: bool, log_lines: usize) -> String {
+    let mut points = Vec::new();
+    for checkout in &results.checkouts {
+        if tests && !checkout.tests.is_empty() {
+            test_points(checkout, log_lines, &mut points);
+        } else {
+            points.push(checkout_point(checkout, log_lines));
+        }
+    }
+
+    let mut out = format!("1..{}\n", points.len());
+    for (i, point) in points.iter().enumerate() {
+        out.push_str(&point.render(i + 1));
+    }
+    out
+}
+
+/// A single test point, before it's numbered.
+struct Point {
+    ok: bool,
+    description: String,
+    directive: Option<String>,
+    diagnostics: Vec<String>,
+}
+
+impl Point {
+    fn render(&self, number: usize) -> String {
+        let mut out = format!(
+            "{} {} - {}",
+            if self.ok { "ok" } else { "not ok" },
+            number,
+            escape(&self.description)
+        );
+        if let Some(directive) = &self.directive {
+            out.push_str(" # ");
+            out.push_str(directive);
+        }
+        out.push('\n');
+        for line in &self.diagnostics {
+            out.push_str(format!("# {}", line).trim_end());
+            out.push('\n');
+        }
+        out
+    }
+}
+
+fn checkout_point(checkout: &CheckoutResults, log_lines: usize) -> Point {
+    if checkout.skipped() {
+        return Point {
+            ok: true,
+            description: checkout.name(),
+            directive: Some(format!("SKIP {}", escape(&checkout.describe()))),
+            diagnostics: Vec::new(),
+        };
+    }
+
+    let diagnostics = if checkout.success() {
+        Vec::new()
+    } else {
+        let mut diagnostics = vec![
+            checkout.describe(),
+            format!("log: {}", checkout.output.display()),
+        ];
+        diagnostics.extend(
+            log_tail(&checkout.output, log_lines)
+                .lines()
+                .map(str::to_string),
+        );
+        diagnostics
+    };
+
+    Point {
+        ok: checkout.success(),
+        description: checkout.name(),
+        directive: None,
+        diagnostics,
+    }
+}
+
+fn test_points(checkout: &CheckoutResults, log_lines: usize, points: &mut Vec<Point>) {
+    let name = checkout.name();
+    let log = read(&checkout.output)
+        .map(|log| String::from_utf8_lossy(&log).into_owned())
+        .unwrap_or_default();
+
+    for test in &checkout.tests {
+        let diagnostics = if test.outcome == TestOutcome::Failed {
+            let mut diagnostics = vec![format!("log: {}", checkout.output.display())];
+            diagnostics.extend(test_output(&log, &test.name, log_lines));
+            diagnostics
+        } else {
+            Vec::new()
+        };
+
+        points.push(Point {
+            ok: test.outcome != TestOutcome::Failed,
+            description: format!("{} {}", name, test.name),
+            directive: match test.outcome {
+                TestOutcome::Ignored => Some("SKIP ignored".to_string()),
+                _ => None,
+            },
+            diagnostics,
+        });
+    }
+
+    // a checkout can fail after its tests pass, e.g. in a later step
+    if !checkout.success()
+        && checkout
+            .tests
+            .iter()
+            .all(|t| t.outcome != TestOutcome::Failed)
+    {
+        points.push(checkout_point(checkout, log_lines));
+    }
+}
+
+/// The last `lines` lines of what libtest captured from a failed test,
+/// from its `---- name stdout ----` section. Falls back to the end of the log.
+fn test_output(log: &str, test: &str, lines: usize) -> Vec<String> {
+    let header = format!("---- {} stdout ----", test);
+    let section = log
+        .lines()
+        // lines may have prefixes, from --log-elapsed and --log-streams
+        .skip_while(|line| !line.ends_with(&header))
+        .skip(1)
+        .take_while(|line| !line.ends_with(" stdout ----") && !line.ends_with("failures:"))
+        .collect::<Vec<_>>();
+
+    let mut section = if section.is_empty() {
+        log.lines().collect()
+    } else {
+        section
+    };
+    while section.last().map_or(false, |line| line.trim().is_empty()) {
+        section.pop();
+    }
+    section[section.len().saturating_sub(lines)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect()
+}
+
+/// `#` starts a directive in a description, so it has to be escaped.
+fn escape(description: &str) -> String {
+    description.replace('\\', "\\\\").replace('#', "\\#")
+}
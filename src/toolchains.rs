@@ -17,9 +17,9 @@ mod travis {
     }
 
     /// Get all of the toolchains listed in `.travis.yml`.
-    /// 
+    ///
     /// # Failures
-    /// 
+    ///
     /// If `.travis.yml` is missing, doesn't match the expected structure,
     /// or the language isn't `rust`, there will be an error.
     pub fn get_toolchains_from_travis() -> Result<Vec<String>, Error> {
@@ -33,7 +33,6 @@ mod travis {
 
         Ok(config.rust)
     }
-
 }
 
 /// Get a list of installed rust toolchains, excluding the current default
@@ -56,3 +55,26 @@ pub fn get_installed_toolchains() -> Result<Vec<String>, Error> {
         .map(String::from)
         .collect())
 }
+
+/// Get the version of rustc in the given toolchain,
+/// e.g. `rustc 1.38.0 (625451e37 2019-09-23)`.
+pub fn get_rustc_version(toolchain: &str, install: bool) -> Result<String, Error> {
+    let mut cmd = Command::new("rustup");
+    cmd.arg("run");
+    if install {
+        cmd.arg("--install");
+    }
+    let output = cmd
+        .args(&[toolchain, "rustc", "--version"])
+        .output()
+        .context("could not execute rustup to get the rustc version")?;
+
+    if !output.status.success() {
+        bail!("couldn't get the rustc version of {}", toolchain);
+    }
+
+    let version =
+        String::from_utf8(output.stdout).context("rustc version contained invalid utf-8")?;
+
+    Ok(version.trim().to_string())
+}